@@ -80,6 +80,6 @@ fn print_binary(binary: &[u8], map: HashMap<usize, (usize, usize)>, program: &[I
 
 fn map_sorted<K: Eq + Hash + Ord, V>(map: HashMap<K, V>) -> Vec<(K, V)> {
     let mut data: Vec<_> = map.into_iter().collect();
-    data.sort_unstable_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    data.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
     data
 }
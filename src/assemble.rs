@@ -1,70 +1,183 @@
 use super::*;
 
-use std::io::{Result as IoResult, Write};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Result as IoResult, Write};
+
+const MAX_DEPTH: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    // Jump targets count as destinations.
+    Destination,
+    Source,
+}
+
+#[derive(Debug)]
+pub enum EncodeError {
+    // More indirection than the 2 bit depth fields hold.
+    DepthOutOfRange(Operand, u8),
+    // A `Source::Value` that doesn't fit into the instruction's unit.
+    ImmediateOutOfRange(u32, Unit),
+    // An operand in a form the instruction has no room for. Every form `Instruction` can
+    // express is encodable today, so nothing returns this yet.
+    UnencodableOperand(Operand),
+    Io(io::Error),
+}
+
+impl Clone for EncodeError {
+    fn clone(&self) -> Self {
+        match *self {
+            EncodeError::DepthOutOfRange(operand, depth) => {
+                EncodeError::DepthOutOfRange(operand, depth)
+            }
+            EncodeError::ImmediateOutOfRange(val, unit) => {
+                EncodeError::ImmediateOutOfRange(val, unit)
+            }
+            EncodeError::UnencodableOperand(operand) => EncodeError::UnencodableOperand(operand),
+            EncodeError::Io(ref err) => {
+                EncodeError::Io(io::Error::new(err.kind(), err.to_string()))
+            }
+        }
+    }
+}
+
+impl From<io::Error> for EncodeError {
+    fn from(err: io::Error) -> Self {
+        EncodeError::Io(err)
+    }
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::DepthOutOfRange(operand, depth) => write!(
+                f,
+                "{} has {} levels of indirection, at most {} can be encoded",
+                operand, depth, MAX_DEPTH
+            ),
+            EncodeError::ImmediateOutOfRange(val, unit) => write!(
+                f,
+                "immediate 0x{:X} does not fit into {} byte(s)",
+                val,
+                unit.num_bytes()
+            ),
+            EncodeError::UnencodableOperand(operand) => {
+                write!(f, "{} cannot be encoded in this form", operand)
+            }
+            EncodeError::Io(ref err) => write!(f, "failed to write instruction: {}", err),
+        }
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Operand::Destination => "destination",
+            Operand::Source => "source",
+        })
+    }
+}
+
+impl Error for EncodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            EncodeError::Io(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 impl Instruction {
-    pub fn assemble(&self, mut out: &mut Write) -> IoResult<()> {
+    /// Checks that the instruction has an encoding, without writing anything.
+    pub fn validate(&self) -> Result<(), EncodeError> {
+        if let Some(usd) = self.usd() {
+            check_depth(Operand::Destination, usd.destination.depth)?;
+            match usd.source {
+                Source::Pointer(ref adr) => check_depth(Operand::Source, adr.depth)?,
+                Source::Value(val) => {
+                    let bits = usd.unit.num_bytes() as u32 * 8;
+                    if bits < 32 && val >> bits != 0 {
+                        return Err(EncodeError::ImmediateOutOfRange(val, usd.unit));
+                    }
+                }
+            }
+        }
+        if let Some(adr) = self.address() {
+            check_depth(Operand::Destination, adr.depth)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the encoded instruction to `out`. Nothing is written if the instruction can't be
+    /// encoded.
+    pub fn assemble(&self, mut out: &mut dyn Write) -> Result<(), EncodeError> {
         use Instruction::*;
-        match self {
-            &Mov(ref usd) => mov_add_sub_mul_div(out, 0, &usd),
-            &Add(ref usd) => mov_add_sub_mul_div(out, 1, &usd),
-            &Sub(ref usd) => mov_add_sub_mul_div(out, 2, &usd),
-            &Mul(ref usd) => mov_add_sub_mul_div(out, 3, &usd),
-            &Div(ref usd) => mov_add_sub_mul_div(out, 4, &usd),
-            &Cmp(ref usd) => {
-                assert!(usd.destination.depth <= 3);
+        self.validate()?;
+        let res = match self {
+            Mov(usd) => mov_add_sub_mul_div(out, 0, usd),
+            Add(usd) => mov_add_sub_mul_div(out, 1, usd),
+            Sub(usd) => mov_add_sub_mul_div(out, 2, usd),
+            Mul(usd) => mov_add_sub_mul_div(out, 3, usd),
+            Div(usd) => mov_add_sub_mul_div(out, 4, usd),
+            Cmp(usd) => {
                 out.write_byte(0b00010000 | usd.unit.id() << 2 | usd.destination.depth)?;
                 out.write_byte(usd.source.id() << 7 | usd.source.depth().unwrap_or(0) << 5)?;
                 out.write_short(usd.destination.location)?;
                 match usd.source {
                     Source::Pointer(ref adr) => out.write_short(adr.location)?,
                     Source::Value(ref val) => for i in (0..usd.unit.num_bytes()).rev() {
-                        out.write_byte((val >> i * 8) as u8)?;
+                        out.write_byte((val >> (i * 8)) as u8)?;
                     },
                 }
                 Ok(())
             }
-            &Jg(ref adr) => jump(out, 0, adr),
-            &Je(ref adr) => jump(out, 1, adr),
-            &Jl(ref adr) => jump(out, 2, adr),
-            &Jmp(ref adr) => jump(out, 3, adr),
-            &Int(ref id) => {
+            Jg(adr) => jump(out, 0, adr),
+            Je(adr) => jump(out, 1, adr),
+            Jl(adr) => jump(out, 2, adr),
+            Jmp(adr) => jump(out, 3, adr),
+            Int(id) => {
                 out.write_byte(0b00110000)?;
                 out.write_byte(*id)
             }
-            &Iret => out.write_byte(0b00110001),
-            &And(ref usd) => and_or_xor_not_shl_shr(out, 0, usd),
-            &Or(ref usd) => and_or_xor_not_shl_shr(out, 1, usd),
-            &Xor(ref usd) => and_or_xor_not_shl_shr(out, 2, usd),
-            &Not(ref usd) => and_or_xor_not_shl_shr(out, 3, usd),
-            &Shl(ref usd) => and_or_xor_not_shl_shr(out, 4, usd),
-            &Shr(ref usd) => and_or_xor_not_shl_shr(out, 5, usd),
-        }
+            Iret => out.write_byte(0b00110001),
+            And(usd) => and_or_xor_not_shl_shr(out, 0, usd),
+            Or(usd) => and_or_xor_not_shl_shr(out, 1, usd),
+            Xor(usd) => and_or_xor_not_shl_shr(out, 2, usd),
+            Not(usd) => and_or_xor_not_shl_shr(out, 3, usd),
+            Shl(usd) => and_or_xor_not_shl_shr(out, 4, usd),
+            Shr(usd) => and_or_xor_not_shl_shr(out, 5, usd),
+        };
+        Ok(res?)
+    }
+}
+
+fn check_depth(operand: Operand, depth: u8) -> Result<(), EncodeError> {
+    if depth > MAX_DEPTH {
+        return Err(EncodeError::DepthOutOfRange(operand, depth));
     }
+    Ok(())
 }
 
-fn mov_add_sub_mul_div(mut out: &mut Write, id: u8, usd: &Usd) -> IoResult<()> {
-    assert!(usd.destination.depth <= 3);
+fn mov_add_sub_mul_div(mut out: &mut dyn Write, id: u8, usd: &Usd) -> IoResult<()> {
     out.write_byte(usd.unit.id() << 2 | usd.source.id())?;
     out.write_byte(id << 5 | usd.destination.depth << 2 | usd.source.depth().unwrap_or(0))?;
     out.write_short(usd.destination.location)?;
     match usd.source {
         Source::Pointer(ref adr) => out.write_short(adr.location)?,
         Source::Value(ref val) => for i in (0..usd.unit.num_bytes()).rev() {
-            out.write_byte((val >> i * 8) as u8)?;
+            out.write_byte((val >> (i * 8)) as u8)?;
         },
     }
     Ok(())
 }
 
-fn jump(mut out: &mut Write, id: u8, adr: &Address) -> IoResult<()> {
-    assert!(adr.depth <= 3);
+fn jump(mut out: &mut dyn Write, id: u8, adr: &Address) -> IoResult<()> {
     out.write_byte(0b00100000 | id << 2 | adr.depth)?;
     out.write_short(adr.location)
 }
 
-fn and_or_xor_not_shl_shr(mut out: &mut Write, id: u8, usd: &Usd) -> IoResult<()> {
-    assert!(usd.destination.depth <= 3);
+fn and_or_xor_not_shl_shr(mut out: &mut dyn Write, id: u8, usd: &Usd) -> IoResult<()> {
     out.write_byte(0b01000000 | id)?;
     out.write_byte(
         usd.unit.id() << 6 | usd.destination.depth << 4 | usd.source.depth().unwrap_or(0) << 2
@@ -74,7 +187,7 @@ fn and_or_xor_not_shl_shr(mut out: &mut Write, id: u8, usd: &Usd) -> IoResult<()
     match usd.source {
         Source::Pointer(ref adr) => out.write_short(adr.location)?,
         Source::Value(ref val) => for i in (0..usd.unit.num_bytes()).rev() {
-            out.write_byte((val >> i * 8) as u8)?;
+            out.write_byte((val >> (i * 8)) as u8)?;
         },
     }
     Ok(())
@@ -94,3 +207,72 @@ impl<T: Write> WriteExt for T {
         self.write_all(&[(short >> 8) as u8, (short & 0xFF) as u8])
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mov(unit: Unit, source: Source, depth: u8) -> Instruction {
+        Instruction::Mov(Usd {
+            unit,
+            source,
+            destination: Address {
+                location: 0x100,
+                depth,
+            },
+        })
+    }
+
+    #[test]
+    fn test_encode_errors() {
+        let mut out = Vec::new();
+        match mov(Unit::Byte, Source::Value(300), 0).assemble(&mut out) {
+            Err(EncodeError::ImmediateOutOfRange(300, Unit::Byte)) => {}
+            res => panic!("{:?}", res),
+        }
+        match mov(Unit::Word, Source::Value(0), 4).assemble(&mut out) {
+            Err(EncodeError::DepthOutOfRange(Operand::Destination, 4)) => {}
+            res => panic!("{:?}", res),
+        }
+        let source = Source::Pointer(Address {
+            location: 0x200,
+            depth: 5,
+        });
+        match mov(Unit::Word, source, 0).assemble(&mut out) {
+            Err(EncodeError::DepthOutOfRange(Operand::Source, 5)) => {}
+            res => panic!("{:?}", res),
+        }
+        let jump = Instruction::Jmp(Address {
+            location: 0,
+            depth: 4,
+        });
+        assert!(assemble(&[Instruction::Iret, jump]).is_err());
+        assert!(out.is_empty());
+
+        mov(Unit::Byte, Source::Value(0xFF), 3)
+            .assemble(&mut out)
+            .unwrap();
+        mov(Unit::Dword, Source::Value(0xFFFF_FFFF), 0)
+            .assemble(&mut out)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        let assembly = assembler::assemble("mov byte 0x300, @0x1234\n", 0).unwrap();
+        // The source field holds 16 bits whatever the unit, so an address of depth 0 that is
+        // wider than a byte encodes too.
+        let mut binary = assembly.binary.clone();
+        binary[1] &= !0b11;
+        for bytes in &[assembly.binary, binary] {
+            let ins = disassemble(bytes.iter().cloned())
+                .next()
+                .unwrap()
+                .result
+                .unwrap();
+            let mut out = Vec::new();
+            ins.assemble(&mut out).unwrap();
+            assert_eq!(&out, bytes);
+        }
+    }
+}
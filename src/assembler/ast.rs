@@ -1,11 +1,20 @@
 use super::super::Unit;
+use super::lexer::Position;
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum AstNode {
     Instruction(Instruction),
     LabelDeclaration(Label),
     Directive(Directive),
 }
 
+#[derive(Debug, Clone)]
+pub struct FatNode {
+    pub node: AstNode,
+    pub pos: Position,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Mov(Usd),
     Add(Usd),
@@ -17,7 +26,7 @@ pub enum Instruction {
     Je(Address),
     Jl(Address),
     Jmp(Address),
-    Int(IntegerExpr),
+    Int(FatExpr),
     Iret,
     And(Usd),
     Or(Usd),
@@ -27,33 +36,47 @@ pub enum Instruction {
     Shr(Usd),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Usd {
     pub unit: Unit,
     pub source: Source,
     pub destination: Address,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Source {
-    Value(IntegerExpr),
+    Value(FatExpr),
     Pointer(Address),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum IntegerExpr {
     Literal(i64),
     LineOffset(i64),
+    LabelReference(String),
+}
+
+// An operand and where it starts, for errors about it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FatExpr {
+    pub expr: IntegerExpr,
+    pub pos: Position,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub struct Address {
-    pub location: IntegerExpr,
+    pub location: FatExpr,
     pub depth: u8,
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Label {
     Absolute(String),
     Relative(String),
 }
 
+#[derive(Debug, Clone, PartialEq)]
 pub enum Directive {
     DeclareBytes(usize, Option<u8>),
-    DeclareString(String),
+    DeclareString(Vec<u8>),
 }
@@ -0,0 +1,459 @@
+use super::lexer::{FatError, FatToken, Position, Token};
+use super::{lexer, lower, parser, symbols, Error};
+
+use std::error::Error as StdError;
+use std::fmt;
+
+// A rustc-style report for any assembler error.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub pos: Position,
+    pub help: Option<String>,
+    // Secondary locations, e.g. where a duplicate label was first declared.
+    pub notes: Vec<(Position, String)>,
+}
+
+impl Diagnostic {
+    fn new(message: String, pos: Position) -> Self {
+        Diagnostic {
+            message,
+            pos,
+            help: None,
+            notes: Vec::new(),
+        }
+    }
+
+    fn help<S: Into<String>>(mut self, help: S) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+
+    fn note<S: Into<String>>(mut self, pos: Position, note: S) -> Self {
+        self.notes.push((pos, note.into()));
+        self
+    }
+
+    /// Renders the diagnostic with the offending line of `source` and a caret underneath.
+    pub fn render(&self, file: &str, source: &str) -> String {
+        let lines: Vec<&str> = source.lines().collect();
+        let gutter = self
+            .notes
+            .iter()
+            .map(|&(pos, _)| pos.line)
+            .chain(Some(self.pos.line))
+            .max()
+            .map_or(1, |line| (line + 1).to_string().len());
+        let pad = " ".repeat(gutter);
+
+        let mut out = format!("error: {}\n", self.message);
+        out += &format!(
+            "{}--> {}:{}:{}\n",
+            pad,
+            file,
+            self.pos.line + 1,
+            column(self.pos) + 1
+        );
+        out += &format!("{} |\n", pad);
+        out += &snippet(&lines, self.pos, gutter, "");
+        for &(pos, ref note) in &self.notes {
+            out += &snippet(&lines, pos, gutter, note);
+        }
+        out += &format!("{} |\n", pad);
+        if let Some(ref help) = self.help {
+            out += &format!("{} = help: {}\n", pad, help);
+        }
+        out
+    }
+
+    pub fn to_json(&self, file: &str, source: &str) -> String {
+        let line = source.lines().nth(self.pos.line).unwrap_or("");
+        let notes: Vec<String> = self
+            .notes
+            .iter()
+            .map(|&(pos, ref note)| {
+                format!(
+                    "{{\"line\":{},\"column\":{},\"message\":{}}}",
+                    pos.line + 1,
+                    column(pos) + 1,
+                    json_string(note)
+                )
+            })
+            .collect();
+        format!(
+            "{{\"severity\":\"error\",\"message\":{},\"file\":{},\"line\":{},\"column\":{},\
+             \"length\":{},\"source_line\":{},\"help\":{},\"notes\":[{}]}}",
+            json_string(&self.message),
+            json_string(file),
+            self.pos.line + 1,
+            column(self.pos) + 1,
+            span_len(line, column(self.pos)),
+            json_string(line),
+            self.help
+                .as_ref()
+                .map_or("null".to_owned(), |h| json_string(h)),
+            notes.join(",")
+        )
+    }
+}
+
+/// Renders every error, followed by a summary line.
+pub fn render_all(errors: &[Error], file: &str, source: &str) -> String {
+    let mut out = String::new();
+    for err in errors {
+        out += &Diagnostic::from(err).render(file, source);
+        out.push('\n');
+    }
+    match errors.len() {
+        0 => {}
+        1 => out += "error: aborting due to previous error\n",
+        n => out += &format!("error: aborting due to {} previous errors\n", n),
+    }
+    out
+}
+
+/// A JSON array with one object per error.
+pub fn json_all(errors: &[Error], file: &str, source: &str) -> String {
+    let diags: Vec<_> = errors
+        .iter()
+        .map(|err| Diagnostic::from(err).to_json(file, source))
+        .collect();
+    format!("[{}]", diags.join(","))
+}
+
+fn column(pos: Position) -> usize {
+    if pos.col < 0 {
+        0
+    } else {
+        pos.col as usize
+    }
+}
+
+fn snippet(lines: &[&str], pos: Position, gutter: usize, label: &str) -> String {
+    let line = lines.get(pos.line).cloned().unwrap_or("");
+    let col = column(pos);
+    // Tabs are kept so that the caret lines up with the source line.
+    let indent: String = line
+        .chars()
+        .take(col)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let carets = "^".repeat(span_len(line, col));
+    let label = if label.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", label)
+    };
+    format!(
+        "{:>w$} | {}\n{} | {}{}{}\n",
+        pos.line + 1,
+        line,
+        " ".repeat(gutter),
+        indent,
+        carets,
+        label,
+        w = gutter
+    )
+}
+
+// The length of the token starting at `col`, guessed from the source text.
+fn span_len(line: &str, col: usize) -> usize {
+    let mut chars = line.chars().skip(col).peekable();
+    let len = match chars.peek() {
+        Some(&'\\') => 2,
+        Some(&'"') => {
+            let mut escaped = false;
+            let mut len = 1;
+            for c in chars.skip(1) {
+                len += 1;
+                match c {
+                    '"' if !escaped => break,
+                    '\\' => escaped = !escaped,
+                    _ => escaped = false,
+                }
+            }
+            len
+        }
+        _ => chars
+            .take_while(|c| c.is_alphanumeric() || "_.$+-:".contains(*c))
+            .count(),
+    };
+    len.max(1)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out += "\\\"",
+            '\\' => out += "\\\\",
+            '\n' => out += "\\n",
+            '\r' => out += "\\r",
+            '\t' => out += "\\t",
+            c if (c as u32) < 0x20 => out += &format!("\\u{:04x}", c as u32),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl From<&FatError> for Diagnostic {
+    fn from(err: &FatError) -> Self {
+        use super::lexer::Error::*;
+        let diag = Diagnostic::new(err.error.to_string(), err.pos);
+        match err.error {
+            UnexpectedNewline => diag.help("string literals have to end on the line they start on"),
+            NestedLabel => diag.help("sub-labels are only one level deep: `parent.sub`"),
+            InvalidEscapeSequence(_) => {
+                diag.help("valid escapes are \\0 \\a \\b \\f \\n \\r \\t \\v \\\\ \\\" and \\xHH")
+            }
+            EmptyHexEscape | OddHexEscape(_) => {
+                diag.help("every byte needs two hex digits, e.g. `\\x0A` or `\\xDEADBEEF`")
+            }
+            _ => diag,
+        }
+    }
+}
+
+impl From<&parser::Error> for Diagnostic {
+    fn from(err: &parser::Error) -> Self {
+        use super::parser::Error::*;
+        let diag = Diagnostic::new(err.to_string(), err.pos());
+        match *err {
+            UnexpectedToken(_) => {
+                diag.help("a line starts with a label, an instruction or a directive")
+            }
+            IntegerNotU8(_) => diag.help("byte values range from 0 to 255"),
+            ExpectedOperand(_) => {
+                diag.help("operands have to be on the same line as the instruction")
+            }
+            TooMuchIndirection(_) => {
+                diag.help("an operand takes at most 3 levels of `@` indirection")
+            }
+            _ => diag,
+        }
+    }
+}
+
+impl From<&symbols::Error> for Diagnostic {
+    fn from(err: &symbols::Error) -> Self {
+        match *err {
+            symbols::Error::DuplicateLabel(_, pos, first) => {
+                Diagnostic::new(err.to_string(), pos).note(first, "first declared here")
+            }
+            symbols::Error::OrphanedSubLabel(_, pos) => Diagnostic::new(err.to_string(), pos)
+                .help("declare an absolute label (`name:`) before any sub-label"),
+        }
+    }
+}
+
+impl From<&lower::Error> for Diagnostic {
+    fn from(err: &lower::Error) -> Self {
+        use super::lower::Error::*;
+        let diag = |pos| Diagnostic::new(err.to_string(), pos);
+        match *err {
+            Symbol(ref err) => err.into(),
+            UndefinedLabel(ref lbl, pos) if lbl.starts_with('.') => diag(pos)
+                .help("sub-labels are only visible below their parent; elsewhere use `parent.sub`"),
+            UndefinedLabel(_, pos) => diag(pos),
+            IntegerOutOfRange(_, pos) => {
+                diag(pos).help("addresses are 16 bit and values have to fit the unit")
+            }
+            LineOffsetOutOfRange(_, pos) => diag(pos).help("`$+n` counts instructions, not bytes"),
+            ProgramTooLarge(pos) | Encode(_, pos) => diag(pos),
+        }
+    }
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(err: &Error) -> Self {
+        match *err {
+            Error::Lexer(ref err) => err.into(),
+            Error::Parser(ref err) => err.into(),
+            Error::Lower(ref err) => err.into(),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Token::Instruction(ref ins) => write!(f, "{}", format!("{:?}", ins).to_lowercase()),
+            Token::Directive(ref dir) => write!(f, "{}", format!("{:?}", dir).to_lowercase()),
+            Token::Unit(ref unit) => write!(f, "{}", format!("{:?}", unit).to_lowercase()),
+            Token::At => write!(f, "@"),
+            Token::Comma => write!(f, ","),
+            Token::Dollar => write!(f, "$"),
+            Token::AbsoluteLabel(ref lbl) => write!(f, "{}:", lbl),
+            Token::RelativeLabel(ref lbl) => write!(f, ".{}:", lbl),
+            Token::IntLiteral(int) => write!(f, "{}", int),
+            Token::StringLiteral(ref bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+            Token::LabelReference(ref lbl) => write!(f, "{}", lbl),
+        }
+    }
+}
+
+impl fmt::Display for lexer::Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use super::lexer::Error::*;
+        match *self {
+            WrongCharacter(found, expected) => {
+                write!(
+                    f,
+                    "expected `{}`, found `{}`",
+                    expected,
+                    found.escape_debug()
+                )
+            }
+            MissingCharacter(c) => write!(f, "missing `{}`", c),
+            UnexpectedNewline => write!(f, "unexpected end of line in string literal"),
+            NotADigit => write!(f, "invalid digit in integer literal"),
+            UnexpectedEof => write!(f, "unexpected end of file"),
+            InvalidCharacter(c) => write!(f, "unexpected character `{}`", c.escape_debug()),
+            NestedLabel => write!(f, "sub-labels cannot be nested"),
+            InvalidEscapeSequence(c) => write!(f, "unknown escape sequence `\\{}`", c),
+            EmptyHexEscape => write!(f, "`\\x` escape without any hex digits"),
+            OddHexEscape(n) => write!(f, "`\\x` escape with an odd number of hex digits ({})", n),
+        }
+    }
+}
+
+impl fmt::Display for FatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.pos.line + 1,
+            column(self.pos) + 1,
+            self.error
+        )
+    }
+}
+
+impl StdError for FatError {}
+
+impl fmt::Display for parser::Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use super::parser::Error::*;
+        match *self {
+            UnexpectedEof(_) => write!(f, "unexpected end of file"),
+            UnexpectedToken(FatToken { ref token, .. }) => write!(f, "unexpected `{}`", token),
+            ExpectedStringLiteral(FatToken { ref token, .. }) => {
+                write!(f, "expected a string literal, found `{}`", token)
+            }
+            ExpectedIntLiteral(FatToken { ref token, .. }) => {
+                write!(f, "expected an integer literal, found `{}`", token)
+            }
+            NegativeInteger(FatToken { ref token, .. }) => {
+                write!(f, "expected a non-negative integer, found `{}`", token)
+            }
+            IntegerNotU8(FatToken { ref token, .. }) => {
+                write!(f, "`{}` does not fit into a byte", token)
+            }
+            ExpectedOperand(FatToken { ref token, .. }) => {
+                write!(f, "expected an operand after `{}`", token)
+            }
+            ExpectedComma(FatToken { ref token, .. }) => {
+                write!(f, "expected `,` between operands, found `{}`", token)
+            }
+            TooMuchIndirection(_) => write!(f, "too many levels of indirection"),
+        }
+    }
+}
+
+impl StdError for parser::Error {}
+
+impl fmt::Display for symbols::Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            symbols::Error::DuplicateLabel(ref lbl, ..) => {
+                write!(f, "label `{}` is declared more than once", lbl)
+            }
+            symbols::Error::OrphanedSubLabel(ref lbl, _) => {
+                write!(f, "sub-label `.{}` has no parent label", lbl)
+            }
+        }
+    }
+}
+
+impl StdError for symbols::Error {}
+
+impl fmt::Display for lower::Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use super::lower::Error::*;
+        match *self {
+            Symbol(ref err) => err.fmt(f),
+            UndefinedLabel(ref lbl, _) => write!(f, "undefined label `{}`", lbl),
+            IntegerOutOfRange(int, _) => write!(f, "integer `{}` is out of range", int),
+            LineOffsetOutOfRange(offset, _) => {
+                write!(f, "`$` offset {:+} is outside of the program", offset)
+            }
+            ProgramTooLarge(_) => write!(f, "program does not fit into 64 KiB of memory"),
+            Encode(ref err, _) => err.fmt(f),
+        }
+    }
+}
+
+impl StdError for lower::Error {}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Lexer(ref err) => err.fmt(f),
+            Error::Parser(ref err) => err.fmt(f),
+            Error::Lower(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl StdError for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::super::assemble;
+    use super::*;
+
+    fn diagnose(code: &str) -> Diagnostic {
+        Diagnostic::from(&assemble(code, 0).unwrap_err()[0])
+    }
+
+    #[test]
+    fn test_render() {
+        let code = "main:\n    jmp @.nowhere\n";
+        assert_eq!(
+            diagnose(code).render("prog.asm", code),
+            "error: undefined label `.nowhere`
+ --> prog.asm:2:10
+  |
+2 |     jmp @.nowhere
+  |          ^^^^^^^^
+  |
+  = help: sub-labels are only visible below their parent; elsewhere use `parent.sub`
+"
+        );
+
+        let code = "f:\n.a: iret\n\n\n\n\n\n\n\n.a: iret";
+        assert_eq!(
+            diagnose(code).render("x.asm", code),
+            "error: label `f.a` is declared more than once
+  --> x.asm:10:1
+   |
+10 | .a: iret
+   | ^^^
+ 2 | .a: iret
+   | ^^^ first declared here
+   |
+"
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        let code = "ds \"a\\q\"";
+        assert_eq!(
+            diagnose(code).to_json("s.asm", code),
+            r#"{"severity":"error","message":"unknown escape sequence `\\q`","file":"s.asm","line":1,"column":6,"length":2,"source_line":"ds \"a\\q\"","help":"valid escapes are \\0 \\a \\b \\f \\n \\r \\t \\v \\\\ \\\" and \\xHH","notes":[]}"#
+        );
+    }
+}
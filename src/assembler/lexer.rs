@@ -12,7 +12,7 @@ pub enum Token {
     AbsoluteLabel(String),
     RelativeLabel(String),
     IntLiteral(i64),
-    StringLiteral(String),
+    StringLiteral(Vec<u8>),
     LabelReference(String),
 }
 
@@ -101,7 +101,7 @@ impl FromStr for Unit {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub struct Position {
     pub line: usize,
     pub col: isize,
@@ -133,6 +133,10 @@ pub enum Error {
     NotADigit,
     UnexpectedEof,
     InvalidCharacter(char),
+    NestedLabel,
+    InvalidEscapeSequence(char),
+    EmptyHexEscape,
+    OddHexEscape(usize),
 }
 
 #[derive(Debug, Clone)]
@@ -162,6 +166,10 @@ pub struct Lexer<I> {
     cur_pos: Position,
     cur_char: char,
     eof_hit: bool,
+    // Mnemonics and directives are only keywords at the start of a statement, so that labels
+    // like `add:` can still be referenced.
+    statement_start: bool,
+    last_line: usize,
 }
 
 impl<I: Iterator<Item = char>> Lexer<I> {
@@ -171,6 +179,8 @@ impl<I: Iterator<Item = char>> Lexer<I> {
             cur_pos: Position { line: 0, col: 0 },
             cur_char: '\0', // To signal the initial iteration
             eof_hit: false,
+            statement_start: true,
+            last_line: 0,
         }
     }
 
@@ -191,7 +201,7 @@ impl<I: Iterator<Item = char>> Lexer<I> {
 
     fn collect_while<F2>(
         &mut self,
-        init_predicate: Option<&Fn(char) -> bool>,
+        init_predicate: Option<&dyn Fn(char) -> bool>,
         predicate: F2,
     ) -> String
     where
@@ -249,22 +259,25 @@ impl<I: Iterator<Item = char>> Lexer<I> {
             '$' => simple_token!(Token::Dollar),
             '"' => self.parse_string_literal(),
             '.' => self.parse_relative_label(),
-            c if c.is_digit(10) || c == '-' || c == '+' => self.parse_int_literal(),
+            c if c.is_ascii_digit() || c == '-' || c == '+' => self.parse_int_literal(),
             c if c.is_alphabetic() => {
                 let start = self.cur_pos;
                 // TODO: Inefficient. Turn collect_while into an interator?
                 let string = c.to_string() + &self.collect_while(None, |c| {
-                    c.is_alphabetic() || c.is_digit(10) || c == '_'
+                    c.is_alphabetic() || c.is_ascii_digit() || c == '_'
                 });
-                if let Ok(dir) = Directive::from_str(&string) {
+                let keyword = self.statement_start;
+                if self.cur_char == '.' && !self.eof_hit {
+                    self.parse_qualified_reference(start, string)
+                } else if self.cur_char == ':' && !self.eof_hit {
+                    self.next_input();
+                    Result::token(start, Token::AbsoluteLabel(string))
+                } else if let (true, Ok(dir)) = (keyword, Directive::from_str(&string)) {
                     Result::token(start, Token::Directive(dir))
-                } else if let Ok(ins) = Instruction::from_str(&string) {
+                } else if let (true, Ok(ins)) = (keyword, Instruction::from_str(&string)) {
                     Result::token(start, Token::Instruction(ins))
-                } else if let Ok(unit) = Unit::from_str(&string) {
+                } else if let (false, Ok(unit)) = (keyword, Unit::from_str(&string)) {
                     Result::token(start, Token::Unit(unit))
-                } else if self.cur_char == ':' {
-                    self.next_input();
-                    Result::token(start, Token::AbsoluteLabel(string))
                 } else {
                     Result::token(start, Token::LabelReference(string))
                 }
@@ -274,41 +287,121 @@ impl<I: Iterator<Item = char>> Lexer<I> {
     }
 
     fn parse_string_literal(&mut self) -> Result {
-        // TODO: Escape sequences
-        if self.cur_char == '"' {
-            let start = self.cur_pos;
-            let string = self.collect_while(None, |c| c != '\n' && c != '"');
-            let end_char = self.cur_char;
-            self.next_input();
-            match end_char {
-                '"' => Result::token(start, Token::StringLiteral(string)),
-                '\n' => Result::error(self.cur_pos, Error::UnexpectedNewline),
-                _ if self.eof_hit => Result::error(self.cur_pos, Error::MissingCharacter('"')),
-                c => Result::error(self.cur_pos, Error::WrongCharacter(c, '"')),
+        if self.cur_char != '"' {
+            return Result::error(self.cur_pos, Error::WrongCharacter(self.cur_char, '"'));
+        }
+
+        let start = self.cur_pos;
+        let mut bytes = Vec::new();
+        self.next_input();
+        loop {
+            if self.eof_hit {
+                return Result::error(self.cur_pos, Error::MissingCharacter('"'));
             }
-        } else {
-            Result::error(self.cur_pos, Error::WrongCharacter(self.cur_char, '"'))
+            match self.cur_char {
+                '"' => {
+                    self.next_input();
+                    return Result::token(start, Token::StringLiteral(bytes));
+                }
+                // The newline's own position is already on the next line.
+                '\n' => return Result::error(start, Error::UnexpectedNewline),
+                '\\' => if let Err(err) = self.parse_escape_sequence(&mut bytes) {
+                    return Result::Error(err);
+                },
+                c => {
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                    self.next_input();
+                }
+            }
+        }
+    }
+
+    // Leaves `cur_char` at the first character after the escape sequence.
+    fn parse_escape_sequence(&mut self, bytes: &mut Vec<u8>) -> StdResult<(), FatError> {
+        let start = self.cur_pos;
+        if self.try_next_input().is_none() {
+            return Err(FatError {
+                error: Error::MissingCharacter('"'),
+                pos: self.cur_pos,
+            });
         }
+
+        let byte = match self.cur_char {
+            '0' => 0x00,
+            'a' => 0x07,
+            'b' => 0x08,
+            'f' => 0x0C,
+            'n' => 0x0A,
+            'r' => 0x0D,
+            't' => 0x09,
+            'v' => 0x0B,
+            '\\' => b'\\',
+            '"' => b'"',
+            'x' => return self.parse_hex_escape(start, bytes),
+            c => {
+                return Err(FatError {
+                    error: Error::InvalidEscapeSequence(c),
+                    pos: start,
+                })
+            }
+        };
+        bytes.push(byte);
+        self.next_input();
+        Ok(())
+    }
+
+    // `\xHH...` - any even number of hex digits, two per byte.
+    fn parse_hex_escape(&mut self, start: Position, bytes: &mut Vec<u8>) -> StdResult<(), FatError> {
+        let digits = self.collect_while(None, |c| c.is_ascii_hexdigit());
+        let error = if digits.is_empty() {
+            Error::EmptyHexEscape
+        } else if !digits.len().is_multiple_of(2) {
+            Error::OddHexEscape(digits.len())
+        } else {
+            bytes.extend(digits.as_bytes().chunks(2).map(|pair| {
+                let pair = ::std::str::from_utf8(pair).unwrap();
+                u8::from_str_radix(pair, 16).unwrap()
+            }));
+            return Ok(());
+        };
+        Err(FatError { error, pos: start })
     }
 
     fn parse_relative_label(&mut self) -> Result {
         if self.cur_char == '.' {
             let start = self.cur_pos;
             let string = self.collect_while(Some(&|c: char| c.is_alphabetic() || c == '_'), |c| {
-                c.is_alphabetic() || c.is_digit(10) || c == '_'
+                c.is_alphabetic() || c.is_ascii_digit() || c == '_'
             });
             match self.cur_char {
-                ':' => {
+                '.' if !self.eof_hit => Result::error(self.cur_pos, Error::NestedLabel),
+                ':' if !self.eof_hit => {
                     self.next_input();
                     Result::token(start, Token::RelativeLabel(string))
                 }
-                c => Result::error(self.cur_pos, Error::WrongCharacter(c, ':')),
+                c if string.is_empty() => Result::error(self.cur_pos, Error::InvalidCharacter(c)),
+                _ => Result::token(start, Token::LabelReference(format!(".{}", string))),
             }
         } else {
             Result::error(self.cur_pos, Error::WrongCharacter(self.cur_char, '.'))
         }
     }
 
+    // `parent.sub` - a sub-label referenced through its parent.
+    fn parse_qualified_reference(&mut self, start: Position, parent: String) -> Result {
+        let sub = self.collect_while(Some(&|c: char| c.is_alphabetic() || c == '_'), |c| {
+            c.is_alphabetic() || c.is_ascii_digit() || c == '_'
+        });
+        if sub.is_empty() {
+            Result::error(self.cur_pos, Error::InvalidCharacter(self.cur_char))
+        } else if self.cur_char == '.' && !self.eof_hit {
+            Result::error(self.cur_pos, Error::NestedLabel)
+        } else {
+            Result::token(start, Token::LabelReference(format!("{}.{}", parent, sub)))
+        }
+    }
+
     fn parse_int_literal(&mut self) -> Result {
         let mut start_character = self.cur_char;
         let start = self.cur_pos;
@@ -352,7 +445,7 @@ impl<I: Iterator<Item = char>> Lexer<I> {
                     Result::error(cur_pos, Error::WrongCharacter(c, '0'))
                 }
                 (c1, c2) => if let Some(d1) = c1.to_digit(10) {
-                    if c2.is_whitespace() {
+                    if !c2.is_alphanumeric() {
                         Result::token(start, Token::IntLiteral(d1 as i64 * sign))
                     } else if let Some(d2) = c2.to_digit(10) {
                         parse_digits(10, (d1 * 10 + d2) as i64)
@@ -389,9 +482,23 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
         self.skip_comment();
         self.skip_whitespace();
         if !self.eof_hit {
+            if self.cur_pos.line != self.last_line {
+                self.statement_start = true;
+            }
+            self.last_line = self.cur_pos.line;
+
             let res = self.next_token();
-            if let Result::Error(_) = res {
-                self.skip_line();
+            match res {
+                Result::Success(FatToken {
+                    token: Token::AbsoluteLabel(_),
+                    ..
+                })
+                | Result::Success(FatToken {
+                    token: Token::RelativeLabel(_),
+                    ..
+                }) => self.statement_start = true,
+                Result::Success(_) => self.statement_start = false,
+                Result::Error(_) => self.skip_line(),
             }
             Some(res)
         } else {
@@ -399,3 +506,67 @@ impl<I: Iterator<Item = char>> Iterator for Lexer<I> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lex(code: &str) -> Vec<Result> {
+        Lexer::new(code.chars()).collect()
+    }
+
+    fn string(code: &str) -> Vec<u8> {
+        match lex(code).pop() {
+            Some(Result::Success(FatToken {
+                token: Token::StringLiteral(bytes),
+                ..
+            })) => bytes,
+            res => panic!("{:?}", res),
+        }
+    }
+
+    fn error(code: &str) -> FatError {
+        match lex(code).pop() {
+            Some(Result::Error(err)) => err,
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_escape_sequences() {
+        assert_eq!(string(r#""Hello world!\0""#), b"Hello world!\0");
+        assert_eq!(
+            string(r#""\0\a\b\f\n\r\t\v\\\"""#),
+            vec![0x00, 0x07, 0x08, 0x0C, 0x0A, 0x0D, 0x09, 0x0B, 0x5C, 0x22]
+        );
+        assert_eq!(
+            string(r#""Test 123 \xDEADBEEF""#),
+            b"Test 123 \xDE\xAD\xBE\xEF".to_vec()
+        );
+        assert_eq!(string(r#""\x00ff!""#), vec![0x00, 0xFF, b'!']);
+        assert_eq!(string("\"\u{e4}\""), vec![0xC3, 0xA4]);
+    }
+
+    #[test]
+    fn test_bad_escape_sequences() {
+        let err = error(r#"ds "ab\q""#);
+        match err.error {
+            Error::InvalidEscapeSequence('q') => {}
+            e => panic!("{:?}", e),
+        }
+        assert_eq!(err.pos, Position { line: 0, col: 6 });
+
+        match error(r#"ds "\x""#).error {
+            Error::EmptyHexEscape => {}
+            e => panic!("{:?}", e),
+        }
+        match error(r#"ds "\xABC""#).error {
+            Error::OddHexEscape(3) => {}
+            e => panic!("{:?}", e),
+        }
+        match error(r#"ds "abc\"#).error {
+            Error::MissingCharacter('"') => {}
+            e => panic!("{:?}", e),
+        }
+    }
+}
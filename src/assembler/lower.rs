@@ -0,0 +1,383 @@
+use super::super::{Address, EncodeError, Instruction, Source, Unit, Usd};
+use super::ast;
+use super::ast::{AstNode, FatExpr, FatNode, IntegerExpr};
+use super::lexer::Position;
+use super::symbols;
+use super::symbols::SymbolTable;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Symbol(symbols::Error),
+    UndefinedLabel(String, Position),
+    IntegerOutOfRange(i64, Position),
+    LineOffsetOutOfRange(i64, Position),
+    ProgramTooLarge(Position),
+    Encode(EncodeError, Position),
+}
+
+impl Error {
+    pub fn pos(&self) -> Position {
+        match *self {
+            Error::Symbol(ref err) => err.pos(),
+            Error::UndefinedLabel(_, pos)
+            | Error::IntegerOutOfRange(_, pos)
+            | Error::LineOffsetOutOfRange(_, pos)
+            | Error::ProgramTooLarge(pos)
+            | Error::Encode(_, pos) => pos,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MappingKind {
+    Instruction,
+    Data,
+}
+
+// Which bytes of the binary a source line produced.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub address: u16,
+    pub size: u16,
+    pub pos: Position,
+    pub kind: MappingKind,
+}
+
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    pub origin: u16,
+    pub binary: Vec<u8>,
+    pub symbols: SymbolTable,
+    pub source_map: Vec<Mapping>,
+}
+
+pub type LowerResult<T = Assembly> = Result<T, Error>;
+
+/// Lays out `nodes` starting at `origin` and encodes them, resolving labels and `$` offsets.
+/// Every error is reported, in the order of `nodes`.
+pub fn lower(nodes: &[FatNode], origin: u16) -> Result<Assembly, Vec<Error>> {
+    let mut errors = Vec::new();
+    let layout = layout(nodes, origin, &mut errors);
+    let mut binary = Vec::new();
+    let mut parent: Option<&str> = None;
+    let mut instruction_index = 0;
+
+    for fat in nodes {
+        match fat.node {
+            AstNode::LabelDeclaration(ast::Label::Absolute(ref lbl)) => parent = Some(lbl),
+            AstNode::LabelDeclaration(ast::Label::Relative(_)) => {}
+            AstNode::Instruction(ref ins) => {
+                let resolver = Resolver {
+                    layout: &layout,
+                    parent,
+                    instruction_index,
+                };
+                let res = resolver.instruction(ins).and_then(|ins| {
+                    ins.assemble(&mut binary)
+                        .map_err(|err| Error::Encode(err, fat.pos))
+                });
+                if let Err(err) = res {
+                    errors.push(err);
+                }
+                instruction_index += 1;
+            }
+            AstNode::Directive(ast::Directive::DeclareBytes(num, val)) => {
+                binary.extend((0..num).map(|_| val.unwrap_or(0)))
+            }
+            AstNode::Directive(ast::Directive::DeclareString(ref bytes)) => {
+                binary.extend(bytes)
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|err| (err.pos().line, err.pos().col));
+        return Err(errors);
+    }
+
+    Ok(Assembly {
+        origin,
+        binary,
+        symbols: layout.symbols,
+        source_map: layout.source_map,
+    })
+}
+
+struct Layout {
+    symbols: SymbolTable,
+    instructions: Vec<u16>,
+    end: u16,
+    source_map: Vec<Mapping>,
+}
+
+// First pass: assigns an address to every label and instruction.
+fn layout(nodes: &[FatNode], origin: u16, errors: &mut Vec<Error>) -> Layout {
+    let mut layout = Layout {
+        symbols: SymbolTable::new(),
+        instructions: Vec::new(),
+        end: origin,
+        source_map: Vec::new(),
+    };
+    let mut address = origin as usize;
+
+    for fat in nodes {
+        let (size, kind) = match fat.node {
+            AstNode::LabelDeclaration(ref lbl) => {
+                let symbols = &mut layout.symbols;
+                let res = match *lbl {
+                    ast::Label::Absolute(ref name) => {
+                        symbols.declare_absolute(name, address as u16, fat.pos)
+                    }
+                    ast::Label::Relative(ref name) => {
+                        symbols.declare_relative(name, address as u16, fat.pos)
+                    }
+                };
+                if let Err(err) = res {
+                    errors.push(Error::Symbol(err));
+                }
+                continue;
+            }
+            AstNode::Instruction(ref ins) => {
+                layout.instructions.push(address as u16);
+                (instruction_size(ins), MappingKind::Instruction)
+            }
+            AstNode::Directive(ast::Directive::DeclareBytes(num, _)) => (num, MappingKind::Data),
+            AstNode::Directive(ast::Directive::DeclareString(ref bytes)) => {
+                (bytes.len(), MappingKind::Data)
+            }
+        };
+
+        if address + size > 0x10000 {
+            errors.push(Error::ProgramTooLarge(fat.pos));
+            break;
+        }
+        layout.source_map.push(Mapping {
+            address: address as u16,
+            size: size as u16,
+            pos: fat.pos,
+            kind,
+        });
+        address += size;
+    }
+
+    layout.end = address as u16;
+    layout
+}
+
+fn instruction_size(ins: &ast::Instruction) -> usize {
+    use super::ast::Instruction::*;
+    match *ins {
+        Mov(ref usd) | Add(ref usd) | Sub(ref usd) | Mul(ref usd) | Div(ref usd) | Cmp(ref usd)
+        | And(ref usd) | Or(ref usd) | Xor(ref usd) | Not(ref usd) | Shl(ref usd)
+        | Shr(ref usd) => match usd.source {
+            ast::Source::Pointer(_) => 6,
+            ast::Source::Value(_) => 4 + usd.unit.num_bytes() as usize,
+        },
+        Jg(_) | Je(_) | Jl(_) | Jmp(_) => 3,
+        Int(_) => 2,
+        Iret => 1,
+    }
+}
+
+// Second pass: turns one AST instruction into an encodable instruction.
+struct Resolver<'a> {
+    layout: &'a Layout,
+    parent: Option<&'a str>,
+    instruction_index: usize,
+}
+
+impl<'a> Resolver<'a> {
+    fn instruction(&self, ins: &ast::Instruction) -> LowerResult<Instruction> {
+        use super::ast::Instruction as Ast;
+        Ok(match *ins {
+            Ast::Mov(ref usd) => Instruction::Mov(self.usd(usd)?),
+            Ast::Add(ref usd) => Instruction::Add(self.usd(usd)?),
+            Ast::Sub(ref usd) => Instruction::Sub(self.usd(usd)?),
+            Ast::Mul(ref usd) => Instruction::Mul(self.usd(usd)?),
+            Ast::Div(ref usd) => Instruction::Div(self.usd(usd)?),
+            Ast::Cmp(ref usd) => Instruction::Cmp(self.usd(usd)?),
+            Ast::Jg(ref adr) => Instruction::Jg(self.address(adr)?),
+            Ast::Je(ref adr) => Instruction::Je(self.address(adr)?),
+            Ast::Jl(ref adr) => Instruction::Jl(self.address(adr)?),
+            Ast::Jmp(ref adr) => Instruction::Jmp(self.address(adr)?),
+            Ast::Int(ref id) => {
+                let val = self.integer(id)?;
+                if !(0..=0xFF).contains(&val) {
+                    return Err(Error::IntegerOutOfRange(val, id.pos));
+                }
+                Instruction::Int(val as u8)
+            }
+            Ast::Iret => Instruction::Iret,
+            Ast::And(ref usd) => Instruction::And(self.usd(usd)?),
+            Ast::Or(ref usd) => Instruction::Or(self.usd(usd)?),
+            Ast::Xor(ref usd) => Instruction::Xor(self.usd(usd)?),
+            Ast::Not(ref usd) => Instruction::Not(self.usd(usd)?),
+            Ast::Shl(ref usd) => Instruction::Shl(self.usd(usd)?),
+            Ast::Shr(ref usd) => Instruction::Shr(self.usd(usd)?),
+        })
+    }
+
+    fn usd(&self, usd: &ast::Usd) -> LowerResult<Usd> {
+        let source = match usd.source {
+            ast::Source::Pointer(ref adr) => Source::Pointer(self.address(adr)?),
+            ast::Source::Value(ref val) => Source::Value(self.value(val, usd.unit)?),
+        };
+        Ok(Usd {
+            unit: usd.unit,
+            source,
+            destination: self.address(&usd.destination)?,
+        })
+    }
+
+    fn address(&self, adr: &ast::Address) -> LowerResult<Address> {
+        let location = self.integer(&adr.location)?;
+        if !(0..=0xFFFF).contains(&location) {
+            return Err(Error::IntegerOutOfRange(location, adr.location.pos));
+        }
+        Ok(Address {
+            location: location as u16,
+            depth: adr.depth,
+        })
+    }
+
+    // Immediates may be given signed or unsigned, as long as they fit into `unit`.
+    fn value(&self, expr: &FatExpr, unit: Unit) -> LowerResult<u32> {
+        let val = self.integer(expr)?;
+        let bits = unit.num_bytes() as u32 * 8;
+        if val < -(1 << (bits - 1)) || val >= 1 << bits {
+            return Err(Error::IntegerOutOfRange(val, expr.pos));
+        }
+        Ok((val as u64 & ((1 << bits) - 1)) as u32)
+    }
+
+    fn integer(&self, fat: &FatExpr) -> LowerResult<i64> {
+        match fat.expr {
+            IntegerExpr::Literal(val) => Ok(val),
+            IntegerExpr::LineOffset(offset) => {
+                let target = self.instruction_index as i64 + offset;
+                if target < 0 || target > self.layout.instructions.len() as i64 {
+                    Err(Error::LineOffsetOutOfRange(offset, fat.pos))
+                } else {
+                    // One past the last instruction is the end of the program.
+                    Ok(*self
+                        .layout
+                        .instructions
+                        .get(target as usize)
+                        .unwrap_or(&self.layout.end) as i64)
+                }
+            }
+            IntegerExpr::LabelReference(ref lbl) => self.layout
+                .symbols
+                .resolve(lbl, self.parent)
+                .map(|sym| sym.address as i64)
+                .ok_or_else(|| Error::UndefinedLabel(lbl.clone(), fat.pos)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::disassemble;
+    use super::super::{assemble, lexer};
+    use super::*;
+
+    #[test]
+    fn test_labels_and_line_offsets() {
+        let assembly = assemble(
+            "add:
+                add @.a, @.b
+                jmp @.ret
+                .a: db 2
+                .b: db 2
+                .ret: db 2 0xFF
+            main:
+                mov add.ret, $+2
+                jmp add
+                mov byte 0x100, -1",
+            0x200,
+        )
+        .unwrap();
+
+        let address = |lbl| assembly.symbols.resolve(lbl, None).unwrap().address;
+        assert_eq!(address("add"), 0x200);
+        assert_eq!(address("add.a"), 0x209);
+        assert_eq!(address("add.ret"), 0x20D);
+        assert_eq!(address("main"), 0x20F);
+        assert_eq!(&assembly.binary[9..15], &[0, 0, 0, 0, 0xFF, 0xFF]);
+
+        let (code, data): (Vec<_>, Vec<_>) = assembly
+            .source_map
+            .iter()
+            .partition(|m| m.kind == MappingKind::Instruction);
+        assert_eq!(code.len(), 5);
+        assert_eq!(data.len(), 3);
+        assert_eq!(code[3].address, 0x215);
+        assert_eq!(code[3].pos.line, 8);
+
+        let main: Vec<_> = disassemble(assembly.binary[0xF..].iter().cloned())
+            .map(|ins| ins.result.unwrap())
+            .collect();
+        assert_eq!(
+            main,
+            vec![
+                Instruction::Mov(Usd {
+                    unit: Unit::Word,
+                    source: Source::Value(0x218),
+                    destination: Address {
+                        location: 0x20D,
+                        depth: 0,
+                    },
+                }),
+                Instruction::Jmp(Address {
+                    location: 0x200,
+                    depth: 0,
+                }),
+                Instruction::Mov(Usd {
+                    unit: Unit::Byte,
+                    source: Source::Value(0xFF),
+                    destination: Address {
+                        location: 0x100,
+                        depth: 0,
+                    },
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |code| assemble(code, 0).unwrap_err().remove(0);
+        match error("jmp nowhere") {
+            super::super::Error::Lower(Error::UndefinedLabel(ref lbl, _)) => {
+                assert_eq!(lbl, "nowhere")
+            }
+            res => panic!("{:?}", res),
+        }
+        match error("a:\n.b: iret\n.b: iret") {
+            super::super::Error::Lower(Error::Symbol(symbols::Error::DuplicateLabel(
+                ref lbl,
+                pos,
+                _,
+            ))) => {
+                assert_eq!(lbl, "a.b");
+                assert_eq!(pos.line, 2);
+            }
+            res => panic!("{:?}", res),
+        }
+        match error("a:\n.b.c: iret") {
+            super::super::Error::Lexer(lexer::FatError {
+                error: lexer::Error::NestedLabel,
+                pos,
+            }) => assert_eq!(pos, Position { line: 1, col: 2 }),
+            res => panic!("{:?}", res),
+        }
+        match error("mov byte 0, 256") {
+            super::super::Error::Lower(Error::IntegerOutOfRange(256, _)) => {}
+            res => panic!("{:?}", res),
+        }
+        match error("jmp $+3\niret") {
+            super::super::Error::Lower(Error::LineOffsetOutOfRange(3, _)) => {}
+            res => panic!("{:?}", res),
+        }
+    }
+}
@@ -1,9 +1,166 @@
 pub mod lexer;
 pub mod parser;
 pub mod ast;
+pub mod lower;
+pub mod symbols;
+pub mod diagnostics;
+
+pub use self::diagnostics::Diagnostic;
+pub use self::lower::Assembly;
 
 pub fn parse<'a, I: IntoIterator<Item = char> + 'a>(
     code: I,
-) -> Box<Iterator<Item = lexer::Result> + 'a> {
+) -> Box<dyn Iterator<Item = lexer::Result> + 'a> {
     Box::new(lexer::Lexer::new(code.into_iter()))
 }
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    Lexer(lexer::FatError),
+    Parser(parser::Error),
+    Lower(lower::Error),
+}
+
+impl Error {
+    pub fn pos(&self) -> lexer::Position {
+        match *self {
+            Error::Lexer(ref err) => err.pos,
+            Error::Parser(ref err) => err.pos(),
+            Error::Lower(ref err) => err.pos(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub origin: u16,
+    // Stop after this many errors. At least 1, or no error gets reported.
+    pub error_limit: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            origin: 0,
+            error_limit: 100,
+        }
+    }
+}
+
+/// Assembles a whole source file into a binary meant to be loaded at `origin`.
+pub fn assemble(code: &str, origin: u16) -> Result<Assembly, Vec<Error>> {
+    assemble_with(
+        code,
+        &Options {
+            origin,
+            ..Options::default()
+        },
+    )
+}
+
+/// Assembles `code`, collecting every lexer and parser error (or else every lowering error)
+/// in source order.
+pub fn assemble_with(code: &str, options: &Options) -> Result<Assembly, Vec<Error>> {
+    let mut errors = Vec::new();
+    let mut tokens = Vec::new();
+    for res in parse(code.chars()) {
+        match res {
+            lexer::Result::Success(tok) => tokens.push(tok),
+            lexer::Result::Error(err) => errors.push(Error::Lexer(err)),
+        }
+    }
+
+    // Whatever is left of a line the lexer choked on would only cause follow-up errors.
+    let broken_lines: Vec<usize> = errors.iter().map(|err| err.pos().line).collect();
+    tokens.retain(|tok| !broken_lines.contains(&tok.pos.line));
+
+    // The parser reports in source order, so past `error_limit` of its own errors none of the
+    // others can be among the first.
+    let mut nodes = Vec::new();
+    let mut parser_errors = 0;
+    if let Some(parser) = parser::parse(tokens) {
+        for node in parser {
+            match node {
+                Ok(node) => nodes.push(node),
+                Err(err) => {
+                    errors.push(Error::Parser(err));
+                    parser_errors += 1;
+                    if parser_errors >= options.error_limit {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        errors.sort_by_key(|err| (err.pos().line, err.pos().col));
+        errors.truncate(options.error_limit);
+        return Err(errors);
+    }
+
+    lower::lower(&nodes, options.origin).map_err(|errors| {
+        errors
+            .into_iter()
+            .take(options.error_limit)
+            .map(Error::Lower)
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BROKEN: &str = "main:
+    mov byte 1 2
+    ds \"ab\\q\"
+    jmp
+    .ok: iret
+    int 0x10 0x11
+    mov @@@@1, 2
+";
+
+    #[test]
+    fn test_collects_all_errors_in_order() {
+        let errors = assemble(BROKEN, 0).unwrap_err();
+        let lines: Vec<_> = errors.iter().map(|err| err.pos().line).collect();
+        assert_eq!(lines, vec![1, 2, 3, 5, 6]);
+        match errors[1] {
+            Error::Lexer(lexer::FatError {
+                error: lexer::Error::InvalidEscapeSequence('q'),
+                ..
+            }) => {}
+            ref err => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn test_error_limit() {
+        let options = Options {
+            error_limit: 2,
+            ..Options::default()
+        };
+        assert_eq!(assemble_with(BROKEN, &options).unwrap_err().len(), 2);
+    }
+
+    #[test]
+    fn test_error_limit_keeps_first_errors() {
+        // Lexer errors come after the parser errors here.
+        let code = "jmp\njmp\nds \"\\q\"\nds \"\\q\"\n";
+        let options = Options {
+            error_limit: 2,
+            ..Options::default()
+        };
+        let errors = assemble_with(code, &options).unwrap_err();
+        let lines: Vec<_> = errors.iter().map(|err| err.pos().line).collect();
+        assert_eq!(lines, vec![0, 1]);
+        assert!(errors.iter().all(|err| matches!(*err, Error::Parser(_))));
+    }
+
+    #[test]
+    fn test_lowering_errors_are_collected() {
+        let errors = assemble("jmp a\njmp b\nmov byte 0, 300", 0).unwrap_err();
+        assert_eq!(errors.len(), 3);
+    }
+}
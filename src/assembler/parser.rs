@@ -1,14 +1,18 @@
 use super::lexer;
 use super::lexer::{FatToken, Position, Token};
 use super::ast;
-use super::ast::AstNode;
+use super::ast::{AstNode, FatNode};
+use super::super::Unit;
+
+use std::iter::Peekable;
 
 pub fn parse<I: IntoIterator<Item = FatToken>>(tokens: I) -> Option<Parser<I::IntoIter>> {
-    let mut input = tokens.into_iter();
+    let mut input = tokens.into_iter().peekable();
     if let Some(first) = input.next() {
         Some(Parser {
             input,
             cur_token: first,
+            started: false,
         })
     } else {
         None
@@ -17,6 +21,7 @@ pub fn parse<I: IntoIterator<Item = FatToken>>(tokens: I) -> Option<Parser<I::In
 
 pub type ParseResult<T = AstNode> = Result<T, Error>;
 
+#[derive(Debug, Clone)]
 pub enum Error {
     UnexpectedEof(Position),
     UnexpectedToken(FatToken),
@@ -24,6 +29,26 @@ pub enum Error {
     ExpectedIntLiteral(FatToken),
     NegativeInteger(FatToken),
     IntegerNotU8(FatToken),
+    ExpectedOperand(FatToken),
+    ExpectedComma(FatToken),
+    TooMuchIndirection(FatToken),
+}
+
+impl Error {
+    pub fn pos(&self) -> Position {
+        use self::Error::*;
+        match *self {
+            UnexpectedEof(pos) => pos,
+            UnexpectedToken(ref tok)
+            | ExpectedStringLiteral(ref tok)
+            | ExpectedIntLiteral(ref tok)
+            | NegativeInteger(ref tok)
+            | IntegerNotU8(ref tok)
+            | ExpectedOperand(ref tok)
+            | ExpectedComma(ref tok)
+            | TooMuchIndirection(ref tok) => tok.pos,
+        }
+    }
 }
 
 struct Eof(Position);
@@ -34,9 +59,11 @@ impl From<Eof> for Error {
     }
 }
 
-pub struct Parser<I> {
-    input: I,
+pub struct Parser<I: Iterator> {
+    input: Peekable<I>,
     cur_token: FatToken,
+    // The first token is already in `cur_token` before the first iteration.
+    started: bool,
 }
 
 impl<I: Iterator<Item = FatToken>> Parser<I> {
@@ -50,6 +77,43 @@ impl<I: Iterator<Item = FatToken>> Parser<I> {
         }
     }
 
+    // Operands have to be on the same line as their instruction or directive.
+    fn peek_on_line(&mut self) -> Option<&Token> {
+        let line = self.cur_token.pos.line;
+        match self.input.peek() {
+            Some(next) if next.pos.line == line => Some(&next.token),
+            _ => None,
+        }
+    }
+
+    fn next_on_line(&mut self) -> ParseResult<FatToken> {
+        if self.peek_on_line().is_some() {
+            Ok(self.next_token()?)
+        } else {
+            Err(Error::ExpectedOperand(self.cur_token.clone()))
+        }
+    }
+
+    fn next_fat_node(&mut self) -> ParseResult<FatNode> {
+        let pos = self.cur_token.pos;
+        let res = self.next_node().map(|node| FatNode { node, pos });
+        if res.is_err() {
+            self.synchronize();
+        }
+        res
+    }
+
+    // Skips the rest of a broken statement: everything up to the next line or label.
+    fn synchronize(&mut self) {
+        loop {
+            match self.peek_on_line() {
+                None | Some(&Token::AbsoluteLabel(_)) | Some(&Token::RelativeLabel(_)) => break,
+                Some(_) => {}
+            }
+            let _ = self.next_token();
+        }
+    }
+
     fn next_node(&mut self) -> ParseResult {
         match self.cur_token.token {
             Token::AbsoluteLabel(ref lbl) => {
@@ -59,12 +123,116 @@ impl<I: Iterator<Item = FatToken>> Parser<I> {
                 Ok(AstNode::LabelDeclaration(ast::Label::Relative(lbl.clone())))
             }
             Token::Directive(_) => self.parse_directive(),
-            _ => unimplemented!(),
+            Token::Instruction(_) => self.parse_instruction(),
+            _ => Err(Error::UnexpectedToken(self.cur_token.clone())),
         }
     }
 
+    fn parse_instruction(&mut self) -> ParseResult {
+        use super::lexer::Instruction as Lex;
+        use super::ast::Instruction::*;
+
+        let ins = if let Token::Instruction(ref ins) = self.cur_token.token {
+            ins.clone()
+        } else {
+            return Err(Error::UnexpectedToken(self.cur_token.clone()));
+        };
+
+        let ins = match ins {
+            Lex::Mov => Mov(self.parse_usd()?),
+            Lex::Add => Add(self.parse_usd()?),
+            Lex::Sub => Sub(self.parse_usd()?),
+            Lex::Mul => Mul(self.parse_usd()?),
+            Lex::Div => Div(self.parse_usd()?),
+            Lex::Cmp => Cmp(self.parse_usd()?),
+            Lex::Jg => Jg(self.parse_address()?),
+            Lex::Je => Je(self.parse_address()?),
+            Lex::Jl => Jl(self.parse_address()?),
+            Lex::Jmp => Jmp(self.parse_address()?),
+            Lex::Int => Int(self.parse_integer_expr()?),
+            Lex::Iret => Iret,
+            Lex::And => And(self.parse_usd()?),
+            Lex::Or => Or(self.parse_usd()?),
+            Lex::Xor => Xor(self.parse_usd()?),
+            Lex::Not => Not(self.parse_usd()?),
+            Lex::Shl => Shl(self.parse_usd()?),
+            Lex::Shr => Shr(self.parse_usd()?),
+        };
+        Ok(AstNode::Instruction(ins))
+    }
+
+    // [unit] destination, source - the unit defaults to word.
+    fn parse_usd(&mut self) -> ParseResult<ast::Usd> {
+        let unit = match self.peek_on_line() {
+            Some(&Token::Unit(lexer::Unit::Byte)) => Some(Unit::Byte),
+            Some(&Token::Unit(lexer::Unit::Word)) => Some(Unit::Word),
+            Some(&Token::Unit(lexer::Unit::Dword)) => Some(Unit::Dword),
+            _ => None,
+        };
+        if unit.is_some() {
+            self.next_token()?;
+        }
+
+        let destination = self.parse_address()?;
+        match self.next_on_line()?.token {
+            Token::Comma => {}
+            _ => return Err(Error::ExpectedComma(self.cur_token.clone())),
+        }
+
+        let depth = self.parse_indirection()?;
+        let location = self.parse_integer_expr()?;
+        let source = if depth == 0 {
+            ast::Source::Value(location)
+        } else {
+            ast::Source::Pointer(ast::Address { location, depth })
+        };
+
+        Ok(ast::Usd {
+            unit: unit.unwrap_or(Unit::Word),
+            source,
+            destination,
+        })
+    }
+
+    fn parse_address(&mut self) -> ParseResult<ast::Address> {
+        let depth = self.parse_indirection()?;
+        let location = self.parse_integer_expr()?;
+        Ok(ast::Address { location, depth })
+    }
+
+    // Counts the `@` symbols in front of an operand, at most 3.
+    fn parse_indirection(&mut self) -> ParseResult<u8> {
+        let mut depth = 0;
+        while let Some(&Token::At) = self.peek_on_line() {
+            self.next_token()?;
+            depth += 1;
+            if depth > 3 {
+                return Err(Error::TooMuchIndirection(self.cur_token.clone()));
+            }
+        }
+        Ok(depth)
+    }
+
+    fn parse_integer_expr(&mut self) -> ParseResult<ast::FatExpr> {
+        let FatToken { token, pos } = self.next_on_line()?;
+        let expr = match token {
+            Token::IntLiteral(int) => ast::IntegerExpr::Literal(int),
+            Token::LabelReference(lbl) => ast::IntegerExpr::LabelReference(lbl),
+            Token::Dollar => {
+                if let Some(&Token::IntLiteral(offset)) = self.peek_on_line() {
+                    self.next_token()?;
+                    ast::IntegerExpr::LineOffset(offset)
+                } else {
+                    ast::IntegerExpr::LineOffset(0)
+                }
+            }
+            _ => return Err(Error::ExpectedOperand(self.cur_token.clone())),
+        };
+        Ok(ast::FatExpr { expr, pos })
+    }
+
     fn parse_int_literal(&mut self) -> ParseResult<i64> {
-        if let Token::IntLiteral(int) = self.next_token()?.token {
+        if let Token::IntLiteral(int) = self.next_on_line()?.token {
             Ok(int)
         } else {
             Err(Error::ExpectedIntLiteral(self.cur_token.clone()))
@@ -82,15 +250,15 @@ impl<I: Iterator<Item = FatToken>> Parser<I> {
 
     fn parse_u8(&mut self) -> ParseResult<u8> {
         let int = self.parse_int_literal()?;
-        if int >= 0 && int <= 255 {
+        if (0..=255).contains(&int) {
             Ok(int as u8)
         } else {
             Err(Error::IntegerNotU8(self.cur_token.clone()))
         }
     }
 
-    fn parse_string_literal(&mut self) -> ParseResult<String> {
-        if let Token::StringLiteral(string) = self.next_token()?.token {
+    fn parse_string_literal(&mut self) -> ParseResult<Vec<u8>> {
+        if let Token::StringLiteral(string) = self.next_on_line()?.token {
             Ok(string)
         } else {
             Err(Error::ExpectedStringLiteral(self.cur_token.clone()))
@@ -102,7 +270,11 @@ impl<I: Iterator<Item = FatToken>> Parser<I> {
             match dir {
                 lexer::Directive::Db => {
                     let num = self.parse_usize()?;
-                    let val = self.parse_u8().ok();
+                    let val = if let Some(&Token::IntLiteral(_)) = self.peek_on_line() {
+                        Some(self.parse_u8()?)
+                    } else {
+                        None
+                    };
                     Ok(AstNode::Directive(ast::Directive::DeclareBytes(num, val)))
                 }
                 lexer::Directive::Ds => Ok(AstNode::Directive(ast::Directive::DeclareString(
@@ -116,12 +288,136 @@ impl<I: Iterator<Item = FatToken>> Parser<I> {
 }
 
 impl<I: Iterator<Item = FatToken>> Iterator for Parser<I> {
-    type Item = ParseResult;
+    type Item = ParseResult<FatNode>;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Ok(_) = self.next_token() {
-            Some(self.next_node())
+        if !self.started {
+            self.started = true;
+            Some(self.next_fat_node())
+        } else if self.next_token().is_ok() {
+            Some(self.next_fat_node())
         } else {
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ast::*;
+
+    fn parse_str(code: &str) -> Vec<AstNode> {
+        let tokens = lexer::Lexer::new(code.chars()).map(|res| match res {
+            lexer::Result::Success(tok) => tok,
+            lexer::Result::Error(err) => panic!("{:?}", err),
+        });
+        parse(tokens)
+            .unwrap()
+            .map(|node| match node {
+                Ok(node) => node.node,
+                Err(err) => panic!("{:?}", err),
+            })
+            .collect()
+    }
+
+    fn at(expr: IntegerExpr, line: usize, col: isize) -> FatExpr {
+        FatExpr {
+            expr,
+            pos: Position { line, col },
+        }
+    }
+
+    fn adr(location: FatExpr, depth: u8) -> Address {
+        Address { location, depth }
+    }
+
+    #[test]
+    fn test_usd_operands() {
+        assert_eq!(
+            parse_str(
+                "cmp byte 100, @@22
+                 mov @@@0x100, -0x200
+                 add dword @.a, add.b"
+            ),
+            vec![
+                AstNode::Instruction(Instruction::Cmp(Usd {
+                    unit: Unit::Byte,
+                    source: Source::Pointer(adr(at(IntegerExpr::Literal(22), 0, 16), 2)),
+                    destination: adr(at(IntegerExpr::Literal(100), 0, 9), 0),
+                })),
+                AstNode::Instruction(Instruction::Mov(Usd {
+                    unit: Unit::Word,
+                    source: Source::Value(at(IntegerExpr::Literal(-0x200), 1, 31)),
+                    destination: adr(at(IntegerExpr::Literal(0x100), 1, 24), 3),
+                })),
+                AstNode::Instruction(Instruction::Add(Usd {
+                    unit: Unit::Dword,
+                    source: Source::Value(at(
+                        IntegerExpr::LabelReference("add.b".to_owned()),
+                        2,
+                        32
+                    )),
+                    destination: adr(at(IntegerExpr::LabelReference(".a".to_owned()), 2, 28), 1),
+                })),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jumps_int_and_line_offsets() {
+        assert_eq!(
+            parse_str(
+                "main:
+                 jmp @.ret
+                 mov dword 100, $+2
+                 je $
+                 int 0x12
+                 iret
+                 .ret: db 2"
+            ),
+            vec![
+                AstNode::LabelDeclaration(Label::Absolute("main".to_owned())),
+                AstNode::Instruction(Instruction::Jmp(adr(
+                    at(IntegerExpr::LabelReference(".ret".to_owned()), 1, 22),
+                    1
+                ))),
+                AstNode::Instruction(Instruction::Mov(Usd {
+                    unit: Unit::Dword,
+                    source: Source::Value(at(IntegerExpr::LineOffset(2), 2, 32)),
+                    destination: adr(at(IntegerExpr::Literal(100), 2, 27), 0),
+                })),
+                AstNode::Instruction(Instruction::Je(adr(
+                    at(IntegerExpr::LineOffset(0), 3, 20),
+                    0
+                ))),
+                AstNode::Instruction(Instruction::Int(at(IntegerExpr::Literal(0x12), 4, 21))),
+                AstNode::Instruction(Instruction::Iret),
+                AstNode::LabelDeclaration(Label::Relative("ret".to_owned())),
+                AstNode::Directive(Directive::DeclareBytes(2, None)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let parse_err = |code: &str| {
+            let tokens = lexer::Lexer::new(code.chars()).filter_map(|res| match res {
+                lexer::Result::Success(tok) => Some(tok),
+                lexer::Result::Error(_) => None,
+            });
+            parse(tokens).unwrap().next().unwrap().map(|node| node.node)
+        };
+        match parse_err("mov @@@@1, 2") {
+            Err(Error::TooMuchIndirection(_)) => {}
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+        match parse_err("mov 1 2") {
+            Err(Error::ExpectedComma(_)) => {}
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+        match parse_err("jmp\n 5") {
+            Err(Error::ExpectedOperand(_)) => {}
+            res => panic!("{:?}", res.map(|_| ())),
+        }
+    }
+}
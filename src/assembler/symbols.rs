@@ -0,0 +1,234 @@
+use super::lexer::Position;
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub enum Error {
+    // The label, where it was declared again and where it was first declared.
+    DuplicateLabel(String, Position, Position),
+    OrphanedSubLabel(String, Position),
+}
+
+impl Error {
+    pub fn pos(&self) -> Position {
+        match *self {
+            Error::DuplicateLabel(_, pos, _) | Error::OrphanedSubLabel(_, pos) => pos,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u16,
+    pub pos: Position,
+}
+
+#[derive(Debug, Clone)]
+pub struct Label {
+    pub symbol: Symbol,
+    pub sub_labels: Vec<Symbol>,
+}
+
+// Absolute labels and the sub-labels declared below them, in declaration order.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    labels: Vec<Label>,
+    index: HashMap<String, usize>,
+    // The label sub-labels are declared under: the one last declared, or the first of its name
+    // if it was declared again.
+    scope: Option<usize>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn declare_absolute(
+        &mut self,
+        name: &str,
+        address: u16,
+        pos: Position,
+    ) -> Result<(), Error> {
+        if let Some(&i) = self.index.get(name) {
+            self.scope = Some(i);
+            let prev = self.labels[i].symbol.pos;
+            return Err(Error::DuplicateLabel(name.to_owned(), pos, prev));
+        }
+        self.scope = Some(self.labels.len());
+        self.index.insert(name.to_owned(), self.labels.len());
+        self.labels.push(Label {
+            symbol: Symbol {
+                name: name.to_owned(),
+                address,
+                pos,
+            },
+            sub_labels: Vec::new(),
+        });
+        Ok(())
+    }
+
+    /// Declares `.name` under the most recently declared absolute label. If that was a
+    /// duplicate, it goes under the label of that name, like `.name` references resolve.
+    pub fn declare_relative(
+        &mut self,
+        name: &str,
+        address: u16,
+        pos: Position,
+    ) -> Result<(), Error> {
+        let parent = match self.scope {
+            Some(i) => &mut self.labels[i],
+            None => return Err(Error::OrphanedSubLabel(name.to_owned(), pos)),
+        };
+        if let Some(prev) = parent.sub_labels.iter().find(|sub| sub.name == name) {
+            return Err(Error::DuplicateLabel(
+                format!("{}.{}", parent.symbol.name, name),
+                pos,
+                prev.pos,
+            ));
+        }
+        parent.sub_labels.push(Symbol {
+            name: name.to_owned(),
+            address,
+            pos,
+        });
+        Ok(())
+    }
+
+    /// Looks up `name`, `parent.sub` or - relative to `scope` - `.sub`.
+    pub fn resolve(&self, reference: &str, scope: Option<&str>) -> Option<&Symbol> {
+        if reference.starts_with('.') {
+            scope.and_then(|parent| self.get_sub(parent, &reference[1..]))
+        } else if let Some(dot) = reference.find('.') {
+            self.get_sub(&reference[..dot], &reference[dot + 1..])
+        } else {
+            self.get(reference)
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.label(name).map(|lbl| &lbl.symbol)
+    }
+
+    pub fn get_sub(&self, parent: &str, name: &str) -> Option<&Symbol> {
+        self.label(parent)
+            .and_then(|lbl| lbl.sub_labels.iter().find(|sub| sub.name == name))
+    }
+
+    pub fn label(&self, name: &str) -> Option<&Label> {
+        self.index.get(name).map(|i| &self.labels[*i])
+    }
+
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    /// Every symbol with its fully qualified name, sorted by address.
+    pub fn qualified(&self) -> Vec<(String, &Symbol)> {
+        let mut symbols: Vec<_> = self
+            .labels
+            .iter()
+            .flat_map(|lbl| {
+                let parent = &lbl.symbol;
+                Some((parent.name.clone(), parent)).into_iter().chain(
+                    lbl.sub_labels
+                        .iter()
+                        .map(move |sub| (format!("{}.{}", parent.name, sub.name), sub)),
+                )
+            })
+            .collect();
+        symbols.sort_by_key(|&(_, sym)| sym.address);
+        symbols
+    }
+
+    /// The symbol `address` lies under: the one with the highest address at or below it,
+    /// preferring sub-labels.
+    pub fn containing(&self, address: u16) -> Option<(String, &Symbol)> {
+        self.qualified()
+            .into_iter()
+            .rev()
+            .find(|&(_, sym)| sym.address <= address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: usize) -> Position {
+        Position { line, col: 0 }
+    }
+
+    #[test]
+    fn test_scoped_resolution() {
+        let mut table = SymbolTable::new();
+        table.declare_absolute("add", 0x10, pos(0)).unwrap();
+        table.declare_relative("a", 0x20, pos(1)).unwrap();
+        table.declare_relative("ret", 0x22, pos(2)).unwrap();
+        table.declare_absolute("main", 0x30, pos(3)).unwrap();
+        table.declare_relative("a", 0x40, pos(4)).unwrap();
+
+        assert_eq!(table.resolve(".a", Some("add")).unwrap().address, 0x20);
+        assert_eq!(table.resolve(".a", Some("main")).unwrap().address, 0x40);
+        assert_eq!(
+            table.resolve("add.ret", Some("main")).unwrap().address,
+            0x22
+        );
+        assert_eq!(table.resolve("main", None).unwrap().address, 0x30);
+        assert!(table.resolve(".ret", Some("main")).is_none());
+        assert!(table.resolve(".a", None).is_none());
+        assert!(table.resolve("nope.a", None).is_none());
+
+        let names: Vec<_> = table
+            .qualified()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["add", "add.a", "add.ret", "main", "main.a"]);
+
+        let containing = |address| table.containing(address).map(|(name, _)| name);
+        assert_eq!(containing(0x0F), None);
+        assert_eq!(containing(0x21), Some("add.a".to_owned()));
+        assert_eq!(containing(0x30), Some("main".to_owned()));
+        assert_eq!(containing(0xFFFF), Some("main.a".to_owned()));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut table = SymbolTable::new();
+        match table.declare_relative("a", 0, pos(0)) {
+            Err(Error::OrphanedSubLabel(ref name, _)) => assert_eq!(name, "a"),
+            res => panic!("{:?}", res),
+        }
+        table.declare_absolute("f", 0, pos(1)).unwrap();
+        table.declare_relative("a", 0, pos(2)).unwrap();
+        match table.declare_relative("a", 0, pos(3)) {
+            Err(Error::DuplicateLabel(ref name, new, old)) => {
+                assert_eq!(name, "f.a");
+                assert_eq!((new.line, old.line), (3, 2));
+            }
+            res => panic!("{:?}", res),
+        }
+        match table.declare_absolute("f", 0, pos(4)) {
+            Err(Error::DuplicateLabel(ref name, _, old)) => {
+                assert_eq!(name, "f");
+                assert_eq!(old.line, 1);
+            }
+            res => panic!("{:?}", res),
+        }
+    }
+
+    #[test]
+    fn test_sub_label_after_duplicate() {
+        let mut table = SymbolTable::new();
+        table.declare_absolute("f", 0x10, pos(0)).unwrap();
+        table.declare_absolute("g", 0x20, pos(1)).unwrap();
+        assert!(table.declare_absolute("f", 0x30, pos(2)).is_err());
+        table.declare_relative("a", 0x30, pos(3)).unwrap();
+
+        // `.a` below the second `f` resolves against `f`, so that is where it is declared.
+        assert_eq!(table.resolve(".a", Some("f")).unwrap().address, 0x30);
+        assert!(table.resolve(".a", Some("g")).is_none());
+    }
+}
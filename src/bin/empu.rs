@@ -0,0 +1,1014 @@
+extern crate empu;
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::fs::File;
+use std::net::TcpListener;
+use std::io::{self, Read, Write};
+use std::process;
+
+use empu::assembler::symbols::SymbolTable;
+use empu::assembler::{self, diagnostics, Options};
+use empu::coverage::Coverage;
+use empu::debugger::{Debugger, GdbServer, Stop, WatchHit, WatchKind, Watchpoint};
+use empu::emulator::{Exception, ExceptionPolicy, Fault, Machine, Services, Step};
+use empu::profile::Profiler;
+use empu::trace::{TraceError, TraceFilter, TraceFormat, TraceReader, Tracer};
+use empu::{disassemble, Instruction, Unit};
+
+const USAGE: &str = "usage: empu <command> [options] [file]
+
+Reads from stdin if no file (or `-`) is given.
+
+commands:
+    asm       assemble a source file into a binary
+    disasm    disassemble a binary into assembly text
+    run       run a program in the emulator
+    debug     step through a program interactively
+    trace     print the entries of a binary trace
+    gdb       serve a program to a GDB remote protocol client
+
+options:
+    -o <file>                    write output to <file> instead of stdout
+    --origin <address>           load address of the program (default 0)
+    --entry <address|label>      where execution starts (default: the origin)
+    --format <asm|bin>           input format of run and debug (default: by file extension)
+    --max-steps <n>              stop running after n instructions (default 1000000)
+    --trap                       let the program handle exceptions like division by zero
+    --trace <file>               trace every instruction of run into <file>
+    --trace-format <text|bin>    format of the trace (default text)
+    --coverage <file>            write the line coverage of run as LCOV into <file> and a
+                                 summary to stderr
+    --profile <file>             profile run, writing folded stacks for flame graphs into
+                                 <file> and a report by label to stderr
+    --address <start>-<end>      only show trace entries at these addresses
+    --mnemonic <name>            only show trace entries of this instruction
+    --listen <host:port|unix:path>
+                                 where gdb waits for its client (default 127.0.0.1:1234)
+    --script <file>              run the debug commands in <file> before reading stdin
+    --error-format <human|json>  how assembler errors are reported (default human)
+    --error-limit <n>            stop after n assembler errors (default 100)
+    -h, --help                   print this message
+";
+
+// `debug` keeps up to 64 * 64 KiB of checkpoints.
+const HISTORY_INTERVAL: u64 = 10_000;
+const HISTORY_CHECKPOINTS: usize = 64;
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+enum Failure {
+    Usage(String),
+    Io(String, io::Error),
+    // Already reported.
+    Reported,
+}
+
+impl From<io::Error> for Failure {
+    fn from(err: io::Error) -> Self {
+        Failure::Io("I/O error".to_owned(), err)
+    }
+}
+
+type CliResult<T = ()> = Result<T, Failure>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Asm,
+    Bin,
+}
+
+struct Args {
+    command: String,
+    input: Option<String>,
+    output: Option<String>,
+    origin: u16,
+    entry: Option<String>,
+    format: Option<Format>,
+    max_steps: u64,
+    trap: bool,
+    trace: Option<String>,
+    profile: Option<String>,
+    coverage: Option<String>,
+    listen: String,
+    script: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    json_errors: bool,
+    error_limit: usize,
+}
+
+fn main() {
+    let code = match parse_args(env::args().skip(1)).and_then(|args| run_command(&args)) {
+        Ok(()) => 0,
+        Err(Failure::Usage(msg)) => {
+            eprintln!("error: {}\n\n{}", msg, USAGE);
+            EXIT_USAGE
+        }
+        Err(Failure::Io(what, err)) => {
+            eprintln!("error: {}: {}", what, err);
+            EXIT_FAILURE
+        }
+        Err(Failure::Reported) => EXIT_FAILURE,
+    };
+    process::exit(code);
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> CliResult<Args> {
+    let mut parsed = Args {
+        command: String::new(),
+        input: None,
+        output: None,
+        origin: 0,
+        entry: None,
+        format: None,
+        max_steps: 1_000_000,
+        trap: false,
+        trace: None,
+        profile: None,
+        coverage: None,
+        listen: "127.0.0.1:1234".to_owned(),
+        script: None,
+        trace_format: TraceFormat::Text,
+        trace_filter: TraceFilter::default(),
+        json_errors: false,
+        error_limit: 100,
+    };
+
+    fn value<I: Iterator<Item = String>>(args: &mut I, flag: &str) -> CliResult<String> {
+        args.next()
+            .ok_or_else(|| Failure::Usage(format!("`{}` expects a value", flag)))
+    }
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            "-o" => parsed.output = Some(value(&mut args, &arg)?),
+            "--origin" => parsed.origin = parse_u16(&value(&mut args, &arg)?)?,
+            "--entry" => parsed.entry = Some(value(&mut args, &arg)?),
+            "--format" => {
+                parsed.format = match value(&mut args, &arg)?.as_str() {
+                    "asm" => Some(Format::Asm),
+                    "bin" => Some(Format::Bin),
+                    f => return Err(Failure::Usage(format!("unknown format `{}`", f))),
+                }
+            }
+            "--max-steps" => parsed.max_steps = parse_number(&value(&mut args, &arg)?)?,
+            "--trap" => parsed.trap = true,
+            "--trace" => parsed.trace = Some(value(&mut args, &arg)?),
+            "--profile" => parsed.profile = Some(value(&mut args, &arg)?),
+            "--coverage" => parsed.coverage = Some(value(&mut args, &arg)?),
+            "--listen" => parsed.listen = value(&mut args, &arg)?,
+            "--script" => parsed.script = Some(value(&mut args, &arg)?),
+            "--trace-format" => {
+                parsed.trace_format = match value(&mut args, &arg)?.as_str() {
+                    "text" => TraceFormat::Text,
+                    "bin" => TraceFormat::Binary,
+                    f => return Err(Failure::Usage(format!("unknown trace format `{}`", f))),
+                }
+            }
+            "--address" => {
+                let range = value(&mut args, &arg)?;
+                let (start, end) = match range.find('-') {
+                    Some(i) => (&range[..i], &range[i + 1..]),
+                    None => (&range[..], &range[..]),
+                };
+                parsed.trace_filter.addresses = Some(parse_u16(start)?..=parse_u16(end)?);
+            }
+            "--mnemonic" => parsed.trace_filter.mnemonic = Some(value(&mut args, &arg)?),
+            "--error-format" => {
+                parsed.json_errors = match value(&mut args, &arg)?.as_str() {
+                    "human" => false,
+                    "json" => true,
+                    f => return Err(Failure::Usage(format!("unknown error format `{}`", f))),
+                }
+            }
+            "--error-limit" => {
+                parsed.error_limit = match parse_number(&value(&mut args, &arg)?)? {
+                    0 => {
+                        return Err(Failure::Usage(
+                            "`--error-limit` must be at least 1".to_owned(),
+                        ))
+                    }
+                    n => n as usize,
+                }
+            }
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(Failure::Usage(format!("unknown option `{}`", arg)))
+            }
+            _ if parsed.command.is_empty() => parsed.command = arg,
+            _ if parsed.input.is_none() => parsed.input = Some(arg),
+            _ => return Err(Failure::Usage(format!("unexpected argument `{}`", arg))),
+        }
+    }
+
+    if parsed.command.is_empty() {
+        return Err(Failure::Usage("no command given".to_owned()));
+    }
+    let hooks = [&parsed.trace, &parsed.profile, &parsed.coverage];
+    if hooks.iter().filter(|hook| hook.is_some()).count() > 1 {
+        return Err(Failure::Usage(
+            "only one of `--trace`, `--profile` and `--coverage` can be given".to_owned(),
+        ));
+    }
+    Ok(parsed)
+}
+
+fn parse_number(s: &str) -> CliResult<u64> {
+    let res = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    res.map_err(|_| Failure::Usage(format!("`{}` is not a number", s)))
+}
+
+fn parse_u16(s: &str) -> CliResult<u16> {
+    let n = parse_number(s)?;
+    if n > 0xFFFF {
+        return Err(Failure::Usage(format!("`{}` is not a 16 bit address", s)));
+    }
+    Ok(n as u16)
+}
+
+fn run_command(args: &Args) -> CliResult {
+    match args.command.as_str() {
+        "asm" => {
+            let assembly = assemble(args)?;
+            write_output(args, &assembly.binary)
+        }
+        "disasm" => {
+            let binary = read_input(args)?;
+            write_output(args, disassemble_listing(&binary, args.origin).as_bytes())
+        }
+        "run" => {
+            let (mut machine, assembly) = load(args)?;
+            if let Some(ref path) = args.trace {
+                trace(&mut machine, args, path)
+            } else if let Some(ref path) = args.profile {
+                profile(&mut machine, &assembly.symbols, args.max_steps, path)
+            } else if let Some(ref path) = args.coverage {
+                coverage(&mut machine, &assembly, args, path)
+            } else {
+                run(&mut machine, args.max_steps)
+            }
+        }
+        "debug" => {
+            let (machine, assembly) = load(args)?;
+            let mut debugger = Debugger::new(machine, assembly.symbols);
+            debugger.record_history(HISTORY_INTERVAL, HISTORY_CHECKPOINTS);
+            debug(&mut debugger, args.max_steps, args.script.as_deref())
+        }
+        "gdb" => {
+            let (machine, assembly) = load(args)?;
+            let mut debugger = Debugger::new(machine, assembly.symbols);
+            gdb(&mut debugger, args)
+        }
+        "trace" => {
+            let listing = trace_listing(&read_input(args)?, &args.trace_filter)
+                .map_err(|err| Failure::Io(format!("cannot read `{}`", input_name(args)), err))?;
+            write_output(args, listing.as_bytes())
+        }
+        cmd => Err(Failure::Usage(format!("unknown command `{}`", cmd))),
+    }
+}
+
+fn input_name(args: &Args) -> &str {
+    match args.input {
+        Some(ref path) if path != "-" => path,
+        _ => "<stdin>",
+    }
+}
+
+fn read_input(args: &Args) -> CliResult<Vec<u8>> {
+    let mut buf = Vec::new();
+    match args.input {
+        Some(ref path) if path != "-" => File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut buf))
+            .map_err(|err| Failure::Io(format!("cannot read `{}`", path), err))?,
+        _ => io::stdin().read_to_end(&mut buf)?,
+    };
+    Ok(buf)
+}
+
+fn write_output(args: &Args, bytes: &[u8]) -> CliResult {
+    match args.output {
+        Some(ref path) if path != "-" => File::create(path)
+            .and_then(|mut f| f.write_all(bytes))
+            .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err)),
+        _ => {
+            let stdout = io::stdout();
+            let mut lock = stdout.lock();
+            lock.write_all(bytes)?;
+            Ok(lock.flush()?)
+        }
+    }
+}
+
+fn assemble(args: &Args) -> CliResult<assembler::Assembly> {
+    let source = String::from_utf8(read_input(args)?).map_err(|_| {
+        Failure::Io(
+            format!("cannot read `{}`", input_name(args)),
+            io::Error::new(io::ErrorKind::InvalidData, "not valid UTF-8"),
+        )
+    })?;
+    let options = Options {
+        origin: args.origin,
+        error_limit: args.error_limit,
+    };
+    match assembler::assemble_with(&source, &options) {
+        Ok(assembly) => Ok(assembly),
+        Err(errors) => {
+            let name = input_name(args);
+            if args.json_errors {
+                eprintln!("{}", diagnostics::json_all(&errors, name, &source));
+            } else {
+                eprint!("{}", diagnostics::render_all(&errors, name, &source));
+            }
+            Err(Failure::Reported)
+        }
+    }
+}
+
+/// The machine, and the assembly if the input is assembly source. A binary assembles to
+/// itself, without symbols.
+fn load(args: &Args) -> CliResult<(Machine, assembler::Assembly)> {
+    let format = args.format.unwrap_or(match args.input {
+        Some(ref path) if !path.ends_with(".asm") && path != "-" => Format::Bin,
+        _ => Format::Asm,
+    });
+
+    let mut machine = Machine::new();
+    machine.set_host(Services::stdio());
+    if args.trap {
+        for exception in &Exception::ALL {
+            machine.set_exception_policy(*exception, ExceptionPolicy::Trap);
+        }
+    }
+    let assembly = match format {
+        Format::Asm => assemble(args)?,
+        Format::Bin => assembler::Assembly {
+            origin: args.origin,
+            binary: read_input(args)?,
+            symbols: SymbolTable::new(),
+            source_map: Vec::new(),
+        },
+    };
+    machine.load(args.origin, &assembly.binary);
+    let entry = match args.entry {
+        Some(ref entry) => location(&assembly.symbols, entry)?,
+        None => args.origin,
+    };
+    machine.set_ip(entry);
+    Ok((machine, assembly))
+}
+
+/// A label or an address.
+fn location(symbols: &SymbolTable, s: &str) -> CliResult<u16> {
+    match symbols.resolve(s, None) {
+        Some(sym) => Ok(sym.address),
+        None => parse_u16(s),
+    }
+}
+
+fn disassemble_listing(binary: &[u8], origin: u16) -> String {
+    let mut out = String::new();
+    for item in disassemble(binary.iter().cloned()) {
+        let address = origin.wrapping_add(item.offset as u16);
+        match item.result {
+            Ok(ins) => out += &format!("{:04X}:  {}\n", address, ins),
+            Err(err) => {
+                for (i, byte) in item.bytes.iter().enumerate() {
+                    let address = address.wrapping_add(i as u16);
+                    out += &format!("{:04X}:  db 1 0x{:02X}  ; {}\n", address, byte, err);
+                }
+            }
+        }
+    }
+    out
+}
+
+fn trace_listing(trace: &[u8], filter: &TraceFilter) -> io::Result<String> {
+    let mut out = String::new();
+    for entry in TraceReader::new(trace)? {
+        let entry = entry?;
+        if filter.matches(&entry) {
+            out += &format!("{}\n", entry);
+        }
+    }
+    Ok(out)
+}
+
+fn run(machine: &mut Machine, max_steps: u64) -> CliResult {
+    let res = machine.run(max_steps);
+    finish(machine, res)
+}
+
+fn trace(machine: &mut Machine, args: &Args, path: &str) -> CliResult {
+    let file = File::create(path)
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    let mut tracer = Tracer::new(io::BufWriter::new(file), args.trace_format)
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    let res = match tracer.run(machine, args.max_steps) {
+        Ok(steps) => Ok(steps),
+        Err(TraceError::Fault(fault)) => Err(fault),
+        Err(TraceError::Io(err)) => {
+            return Err(Failure::Io(format!("cannot write `{}`", path), err))
+        }
+    };
+    finish(machine, res)
+}
+
+fn profile(machine: &mut Machine, symbols: &SymbolTable, max_steps: u64, path: &str) -> CliResult {
+    let mut profiler = Profiler::new();
+    let res = profiler.run(machine, max_steps);
+    // Whatever ran before a fault is still worth reporting.
+    let file = File::create(path)
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    profiler
+        .write_folded(symbols, io::BufWriter::new(file))
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    profiler.write_report(symbols, io::stderr())?;
+    finish(machine, res)
+}
+
+fn coverage(
+    machine: &mut Machine,
+    assembly: &assembler::Assembly,
+    args: &Args,
+    path: &str,
+) -> CliResult {
+    if assembly.source_map.is_empty() {
+        return Err(Failure::Usage(
+            "`--coverage` needs assembly source".to_owned(),
+        ));
+    }
+    let mut coverage = Coverage::new();
+    let res = coverage.run(machine, args.max_steps);
+    let file = File::create(path)
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    coverage
+        .write_lcov(assembly, input_name(args), io::BufWriter::new(file))
+        .map_err(|err| Failure::Io(format!("cannot write `{}`", path), err))?;
+    eprint!("{}", coverage.summary(assembly));
+    finish(machine, res)
+}
+
+fn gdb(debugger: &mut Debugger, args: &Args) -> CliResult {
+    let failed = |err| Failure::Io(format!("cannot listen on `{}`", args.listen), err);
+    let mut server = GdbServer::new(debugger, args.max_steps);
+    if args.listen.starts_with("unix:") {
+        serve_unix(&mut server, &args.listen[5..]).map_err(failed)?;
+    } else {
+        let listener = TcpListener::bind(&args.listen).map_err(failed)?;
+        eprintln!("waiting for gdb on {}", args.listen);
+        let (stream, _) = listener.accept().map_err(failed)?;
+        stream.set_nodelay(true)?;
+        server.serve(stream)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn serve_unix(server: &mut GdbServer, path: &str) -> io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    eprintln!("waiting for gdb on {}", path);
+    let res = listener.accept().and_then(|(stream, _)| server.serve(stream));
+    std::fs::remove_file(path)?;
+    res
+}
+
+#[cfg(not(unix))]
+fn serve_unix(_: &mut GdbServer, _: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are not supported here",
+    ))
+}
+
+/// Reports how a run ended.
+fn finish(machine: &Machine, res: Result<u64, Fault>) -> CliResult {
+    match res {
+        Ok(_) if machine.halted() => Ok(()),
+        Ok(steps) => {
+            eprintln!("stopped after {} steps at 0x{:04X}", steps, machine.ip());
+            Ok(())
+        }
+        Err(fault) => {
+            eprintln!("fault: {}", fault);
+            Err(Failure::Reported)
+        }
+    }
+}
+
+const DEBUG_HELP: &str = "commands:
+    s, step            execute one instruction
+    n, next            like step, but run interrupt handlers to completion
+    c, continue        run until a breakpoint, or until the program halts or faults
+    rs                 step back one instruction
+    rc                 go back to the previous breakpoint or watched write
+    goto <n>           go to the point after n executed instructions
+    b, break <loc>     set a breakpoint
+    d, delete <loc>    delete a breakpoint
+    w, watch <loc> [n] [r|w|c]
+                       watch n bytes (default 1) for reads, writes or changes (default)
+    x[/<n><b|w|d>] <loc>
+                       dump n bytes, words or dwords of memory (default 16 bytes)
+    disas [<loc>] [n]  disassemble n instructions (default 8) from loc (default ip)
+    p, print[/b|w|d] <loc>
+                       print the memory at loc (default a word)
+    set mem[/b|w|d] <loc> <value>
+                       write memory (default a word)
+    set ip <loc>       continue somewhere else
+    i, info            show the machine state, breakpoints and watchpoints
+    history            list the commands so far
+    !!, !<n>           repeat the last or the nth command
+    source <file>      run the commands in file
+    q, quit            leave the debugger
+
+An empty line repeats the last command. <loc> is an address or a label like `main.loop`.
+Like in a source operand, `@` reads memory, so `@0x100` is the memory at 0x100 too, and
+`@@ptr` is the memory `ptr` points to.
+";
+
+fn dump(machine: &Machine, start: u16, count: u64, unit: Unit) {
+    let size = unit.num_bytes() as u64;
+    let per_row = 16 / size;
+    for row in 0..count.div_ceil(per_row) {
+        let address = start.wrapping_add((row * per_row * size) as u16);
+        let values: Vec<String> = (0..per_row.min(count - row * per_row))
+            .map(|i| {
+                let value = machine.peek(address.wrapping_add((i * size) as u16), unit);
+                format!("{:01$X}", value, size as usize * 2)
+            })
+            .collect();
+        eprintln!("{:04X}:  {}", address, values.join(" "));
+    }
+}
+
+// Parses the `16b` of `x/16b`. Both parts are optional.
+fn dump_format(format: &str) -> Option<(u64, Unit)> {
+    let digits = format.trim_end_matches(char::is_alphabetic);
+    let unit = match &format[digits.len()..] {
+        "" => Unit::Byte,
+        suffix => unit(suffix)?,
+    };
+    let count = match digits {
+        "" => 16,
+        digits => parse_number(digits).ok()?,
+    };
+    Some((count, unit))
+}
+
+fn unit(suffix: &str) -> Option<Unit> {
+    match suffix {
+        "b" => Some(Unit::Byte),
+        "w" => Some(Unit::Word),
+        "d" => Some(Unit::Dword),
+        _ => None,
+    }
+}
+
+fn print_step(step: &Step) {
+    for write in &step.writes {
+        let bytes: Vec<String> = write.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+        eprintln!("  0x{:04X} <- {}", write.address, bytes.join(" "));
+    }
+    if let Some(exception) = step.exception {
+        eprintln!("  trapped {:?}", exception);
+    }
+}
+
+fn debug(debugger: &mut Debugger, max_steps: u64, script: Option<&str>) -> CliResult {
+    let mut session = Session::new(debugger, max_steps);
+    if let Some(path) = script {
+        session
+            .source(path)
+            .map_err(|err| Failure::Io(format!("cannot read `{}`", path), err))?;
+    }
+    loop {
+        session.prompt();
+        let line = match session.pending.pop_front() {
+            Some(line) => {
+                eprintln!("{}", line);
+                line
+            }
+            None => {
+                // Without holding the stdin lock, which the program's READ_CHAR needs too.
+                let mut line = String::new();
+                if io::stdin().read_line(&mut line)? == 0 {
+                    return Ok(());
+                }
+                line
+            }
+        };
+        if !session.command(&line) {
+            return Ok(());
+        }
+    }
+}
+
+/// The state of the debugger REPL.
+struct Session<'a> {
+    debugger: &'a mut Debugger,
+    max_steps: u64,
+    // The qualified name of each labelled address, for showing instructions.
+    names: HashMap<u16, String>,
+    history: Vec<String>,
+    // Commands from script files, which run before more are read.
+    pending: VecDeque<String>,
+}
+
+impl<'a> Session<'a> {
+    fn new(debugger: &'a mut Debugger, max_steps: u64) -> Self {
+        let mut names = HashMap::new();
+        for (name, symbol) in debugger.symbols().qualified() {
+            names.entry(symbol.address).or_insert(name);
+        }
+        Session {
+            debugger,
+            max_steps,
+            names,
+            history: Vec::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queues the commands in the file at `path` ahead of everything else. Blank lines and
+    /// lines starting with `#` are skipped.
+    fn source(&mut self, path: &str) -> io::Result<()> {
+        let mut script = String::new();
+        File::open(path)?.read_to_string(&mut script)?;
+        for line in script.lines().rev() {
+            let line = line.trim();
+            if !line.is_empty() && !line.starts_with('#') {
+                self.pending.push_front(line.to_owned());
+            }
+        }
+        Ok(())
+    }
+
+    fn prompt(&mut self) {
+        if self.debugger.machine().halted() {
+            eprintln!("halted");
+            self.debugger.machine_mut().resume();
+        }
+        let machine = self.debugger.machine();
+        match machine.fetch() {
+            Ok((ins, _)) => eprint!(
+                "{}:  {}\n(empu) ",
+                self.describe(machine.ip()),
+                self.symbolized(&ins)
+            ),
+            Err(fault) => eprint!("{}\n(empu) ", fault),
+        }
+    }
+
+    /// Runs a line of input. Returns false once the session should end.
+    fn command(&mut self, line: &str) -> bool {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            self.history
+                .last()
+                .cloned()
+                .unwrap_or_else(|| "step".to_owned())
+        } else if let Some(n) = line.strip_prefix('!') {
+            let n = match n {
+                "!" => Some(self.history.len()),
+                n => n.parse::<usize>().ok(),
+            };
+            match n.and_then(|n| self.history.get(n.wrapping_sub(1))) {
+                Some(command) => {
+                    let command = command.clone();
+                    eprintln!("{}", command);
+                    self.history.push(command.clone());
+                    command
+                }
+                None => {
+                    eprintln!("no command `{}` in the history", line);
+                    return true;
+                }
+            }
+        } else {
+            self.history.push(line.to_owned());
+            line.to_owned()
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        self.execute(&words)
+    }
+
+    fn execute(&mut self, words: &[&str]) -> bool {
+        let (command, format) = split_format(words[0]);
+        let arg = words.get(1).cloned();
+        let res = match (command, arg) {
+            ("s", _) | ("step", _) => self.debugger.step().map(|step| {
+                print_step(&step);
+                for hit in self.debugger.watch_hits(&step) {
+                    self.report_hit(&hit);
+                }
+            }),
+            ("n", _) | ("next", _) => self
+                .debugger
+                .step_over(self.max_steps)
+                .map(|stop| self.report_stop(stop)),
+            ("c", _) | ("continue", _) => self
+                .debugger
+                .cont(self.max_steps)
+                .map(|stop| self.report_stop(stop)),
+            ("rs", _) => {
+                if !self.debugger.step_back() {
+                    self.report_stop(Stop::HistoryStart);
+                }
+                Ok(())
+            }
+            ("rc", _) => {
+                let stop = self.debugger.reverse_cont();
+                self.report_stop(stop);
+                Ok(())
+            }
+            ("goto", Some(n)) => {
+                let recorded = parse_number(n).map(|n| self.debugger.goto(n));
+                if !recorded.unwrap_or(false) {
+                    eprintln!("`{}` is not a recorded step", n);
+                }
+                Ok(())
+            }
+            ("b", Some(loc)) | ("break", Some(loc)) => {
+                if let Some(address) = self.address(loc) {
+                    self.debugger.add_breakpoint(address);
+                    eprintln!("breakpoint at {}", self.describe(address));
+                }
+                Ok(())
+            }
+            ("d", Some(loc)) | ("delete", Some(loc)) => {
+                if let Some(address) = self.address(loc) {
+                    if !self.debugger.remove_breakpoint(address) {
+                        eprintln!("no breakpoint at `{}`", loc);
+                    }
+                }
+                Ok(())
+            }
+            ("w", Some(_)) | ("watch", Some(_)) => {
+                match self.watchpoint(&words[1..]) {
+                    Some(watch) => {
+                        let id = self.debugger.add_watchpoint(watch);
+                        eprintln!("watchpoint {}", id.0);
+                    }
+                    None => eprintln!("usage: watch <loc> [n] [r|w|c]"),
+                }
+                Ok(())
+            }
+            ("x", Some(loc)) => {
+                let format = format.map_or(Some((16, Unit::Byte)), dump_format);
+                let count = words.get(2).map(|n| parse_number(n));
+                match (format, count) {
+                    (Some((count, unit)), None) | (Some((_, unit)), Some(Ok(count))) => {
+                        if let Some(address) = self.address(loc) {
+                            dump(self.debugger.machine(), address, count, unit);
+                        }
+                    }
+                    _ => eprintln!("usage: x[/<n><b|w|d>] <loc>, or x <loc> [n]"),
+                }
+                Ok(())
+            }
+            ("disas", _) => {
+                let start = match arg {
+                    Some(loc) => self.address(loc),
+                    None => Some(self.debugger.machine().ip()),
+                };
+                match (start, words.get(2).map(|n| parse_number(n))) {
+                    (Some(start), None) => self.disassemble(start, 8),
+                    (Some(start), Some(Ok(count))) => self.disassemble(start, count),
+                    (_, Some(Err(_))) => eprintln!("usage: disas [<loc>] [n]"),
+                    (None, _) => {}
+                }
+                Ok(())
+            }
+            ("p", Some(loc)) | ("print", Some(loc)) => {
+                match format.map_or(Some(Unit::Word), unit) {
+                    Some(unit) => self.print(loc, unit),
+                    None => eprintln!("usage: print[/b|w|d] <loc>"),
+                }
+                Ok(())
+            }
+            ("set", Some(what)) => {
+                self.set(what, &words[2..]);
+                Ok(())
+            }
+            ("i", _) | ("info", _) => {
+                self.info();
+                Ok(())
+            }
+            ("history", _) => {
+                for (i, command) in self.history.iter().enumerate() {
+                    eprintln!("{:4}  {}", i + 1, command);
+                }
+                Ok(())
+            }
+            ("source", Some(path)) => {
+                if let Err(err) = self.source(path) {
+                    eprintln!("cannot read `{}`: {}", path, err);
+                }
+                Ok(())
+            }
+            ("q", _) | ("quit", _) => return false,
+            _ => {
+                eprint!("{}", DEBUG_HELP);
+                Ok(())
+            }
+        };
+        if let Err(fault) = res {
+            eprintln!("fault: {}", fault);
+        }
+        true
+    }
+
+    /// The memory an argument refers to. Every `@` but the first follows a pointer.
+    fn address(&self, arg: &str) -> Option<u16> {
+        let name = arg.trim_start_matches('@');
+        let depth = arg.len() - name.len();
+        let start = match location(self.debugger.symbols(), name) {
+            Ok(start) => start,
+            Err(_) => {
+                eprintln!("unknown location `{}`", arg);
+                return None;
+            }
+        };
+        let machine = self.debugger.machine();
+        Some((1..depth).fold(start, |address, _| machine.peek(address, Unit::Word) as u16))
+    }
+
+    /// An address with the label it lies under, like `0206 <main.loop+2>`.
+    fn describe(&self, address: u16) -> String {
+        match self.debugger.symbols().containing(address) {
+            Some((name, symbol)) if symbol.address == address => {
+                format!("{:04X} <{}>", address, name)
+            }
+            Some((name, symbol)) => {
+                format!("{:04X} <{}+{}>", address, name, address - symbol.address)
+            }
+            None => format!("{:04X}", address),
+        }
+    }
+
+    /// An address of data, with its name if it has one.
+    fn describe_data(&self, address: u16) -> String {
+        match self.names.get(&address) {
+            Some(name) => format!("{:04X} <{}>", address, name),
+            None => format!("{:04X}", address),
+        }
+    }
+
+    fn symbolized(&self, ins: &Instruction) -> String {
+        ins.symbolized(|address| self.names.get(&address).cloned())
+            .to_string()
+    }
+
+    fn disassemble(&self, start: u16, count: u64) {
+        let machine = self.debugger.machine();
+        let mut address = start;
+        for _ in 0..count {
+            let window = (0..16).map(|i| machine.peek(address.wrapping_add(i), Unit::Byte) as u8);
+            let item = disassemble(window).next().unwrap();
+            let marker = if address == machine.ip() { "=>" } else { "  " };
+            let length = match item.result {
+                Ok(ref ins) => {
+                    eprintln!(
+                        "{} {}:  {}",
+                        marker,
+                        self.describe(address),
+                        self.symbolized(ins)
+                    );
+                    item.len()
+                }
+                Err(ref err) => {
+                    eprintln!(
+                        "{} {}:  db 1 0x{:02X}  ; {}",
+                        marker,
+                        self.describe(address),
+                        item.bytes[0],
+                        err
+                    );
+                    1
+                }
+            };
+            address = address.wrapping_add(length as u16);
+        }
+    }
+
+    fn print(&self, loc: &str, unit: Unit) {
+        if let Some(address) = self.address(loc) {
+            let value = self.debugger.machine().peek(address, unit);
+            eprintln!(
+                "{} = 0x{:X} ({})  at {}",
+                loc,
+                value,
+                value,
+                self.describe_data(address)
+            );
+        }
+    }
+
+    fn set(&mut self, what: &str, args: &[&str]) {
+        match (split_format(what), args) {
+            (("mem", format), &[loc, value]) => {
+                let unit = format.map_or(Some(Unit::Word), unit);
+                match (unit, parse_number(value)) {
+                    (Some(unit), Ok(value)) => {
+                        if let Some(address) = self.address(loc) {
+                            let machine = self.debugger.machine_mut();
+                            machine.write(address, unit, value as u32);
+                        }
+                    }
+                    _ => eprintln!("usage: set mem[/b|w|d] <loc> <value>"),
+                }
+            }
+            (("ip", None), &[loc]) => {
+                if let Some(address) = self.address(loc) {
+                    self.debugger.machine_mut().set_ip(address);
+                }
+            }
+            _ => eprintln!("usage: set mem[/b|w|d] <loc> <value>, or set ip <loc>"),
+        }
+    }
+
+    fn info(&self) {
+        let machine = self.debugger.machine();
+        eprintln!(
+            "ip 0x{:04X}  flags {}  interrupts {:?}  steps {}  cycles {}",
+            machine.ip(),
+            machine.flags(),
+            machine.interrupts(),
+            machine.steps(),
+            machine.cycles()
+        );
+        let breakpoints: Vec<String> = self
+            .debugger
+            .breakpoints()
+            .map(|address| self.describe(address))
+            .collect();
+        eprintln!("breakpoints {}", breakpoints.join(", "));
+        for &(id, ref watch) in self.debugger.watchpoints() {
+            eprintln!(
+                "watchpoint {}: {:?} of {}..={}",
+                id.0,
+                watch.kind,
+                self.describe_data(*watch.range.start()),
+                self.describe_data(*watch.range.end())
+            );
+        }
+    }
+
+    fn watchpoint(&self, words: &[&str]) -> Option<Watchpoint> {
+        let start = self.address(words[0])?;
+        let count = match words.get(1) {
+            Some(n) => parse_u16(n).ok().filter(|&n| n > 0)?,
+            None => 1,
+        };
+        let kind = match words.get(2).cloned().unwrap_or("c") {
+            "r" => WatchKind::Read,
+            "w" => WatchKind::Write,
+            "c" => WatchKind::Change,
+            _ => return None,
+        };
+        Some(Watchpoint::new(start..=start.wrapping_add(count - 1), kind))
+    }
+
+    fn report_hit(&self, hit: &WatchHit) {
+        let ins = hit
+            .instruction
+            .as_ref()
+            .map_or("?".to_owned(), |ins| self.symbolized(ins));
+        eprintln!(
+            "watchpoint {} at {} ({}): 0x{:04X} 0x{:X} -> 0x{:X}",
+            hit.watchpoint.0,
+            self.describe(hit.address),
+            ins,
+            hit.access,
+            hit.old,
+            hit.new
+        );
+    }
+
+    fn report_stop(&self, stop: Stop) {
+        match stop {
+            Stop::Breakpoint(address) => eprintln!("breakpoint at {}", self.describe(address)),
+            Stop::Watchpoint(hit) => self.report_hit(&hit),
+            Stop::StepLimit => eprintln!("stopped after the step limit"),
+            Stop::HistoryStart => eprintln!("reached the start of the recorded history"),
+            Stop::Stepped | Stop::Condition | Stop::Halted => {}
+        }
+    }
+}
+
+// Splits `x/16b` into `x` and `16b`.
+fn split_format(command: &str) -> (&str, Option<&str>) {
+    match command.find('/') {
+        Some(i) => (&command[..i], Some(&command[i + 1..])),
+        None => (command, None),
+    }
+}
@@ -0,0 +1,360 @@
+use super::*;
+
+use assembler::lower::MappingKind;
+use assembler::Assembly;
+use emulator::{Fault, Flags, Machine, Step};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+
+/// How often a conditional jump went each way.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Branch {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Which instructions a machine executed, and which way its `jg`, `je` and `jl` went.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    // Execution counts by instruction address.
+    executed: BTreeMap<u16, u64>,
+    // By the address of the jump.
+    branches: BTreeMap<u16, Branch>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Executes one instruction and records it.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<Step, Fault> {
+        let flags = machine.flags();
+        let step = machine.step()?;
+        self.record(&step, flags);
+        Ok(step)
+    }
+
+    /// Like `Machine::run`, recording every instruction.
+    pub fn run(&mut self, machine: &mut Machine, max_steps: u64) -> Result<u64, Fault> {
+        let mut steps = 0;
+        while steps < max_steps && !machine.halted() {
+            self.step(machine)?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    /// Records `step`, which started with `flags`.
+    pub fn record(&mut self, step: &Step, flags: Flags) {
+        *self.executed.entry(step.address).or_insert(0) += 1;
+        let taken = match step.instruction {
+            // A jump that trapped went nowhere.
+            _ if step.exception.is_some() => return,
+            Some(Instruction::Jg(_)) => flags.greater,
+            Some(Instruction::Je(_)) => flags.equal,
+            Some(Instruction::Jl(_)) => flags.less,
+            _ => return,
+        };
+        let branch = self.branches.entry(step.address).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    /// How often the instruction at `address` was executed.
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executed.get(&address).cloned().unwrap_or(0)
+    }
+
+    pub fn branch(&self, address: u16) -> Option<Branch> {
+        self.branches.get(&address).cloned()
+    }
+
+    /// Writes the coverage of the source lines of `assembly` as an LCOV tracefile, with
+    /// absolute labels as functions.
+    pub fn write_lcov<W: Write>(
+        &self,
+        assembly: &Assembly,
+        source: &str,
+        mut out: W,
+    ) -> io::Result<()> {
+        let instructions = instructions(assembly);
+        let line = |address| {
+            instructions
+                .iter()
+                .find(|&&(other, _)| other == address)
+                .map(|&(_, line)| line)
+        };
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", source)?;
+
+        let functions: Vec<_> = assembly
+            .symbols
+            .labels()
+            .iter()
+            .filter_map(|label| {
+                let symbol = &label.symbol;
+                line(symbol.address).map(|line| (line, symbol))
+            })
+            .collect();
+        for &(line, symbol) in &functions {
+            writeln!(out, "FN:{},{}", line, symbol.name)?;
+        }
+        for &(_, symbol) in &functions {
+            writeln!(
+                out,
+                "FNDA:{},{}",
+                self.executions(symbol.address),
+                symbol.name
+            )?;
+        }
+        let hit = functions
+            .iter()
+            .filter(|&&(_, symbol)| self.executions(symbol.address) > 0)
+            .count();
+        writeln!(out, "FNF:{}\nFNH:{}", functions.len(), hit)?;
+
+        let (mut found, mut hit) = (0, 0);
+        for (block, &(address, line)) in instructions
+            .iter()
+            .filter(|&&(address, _)| is_branch(assembly, address))
+            .enumerate()
+        {
+            let count = |n: u64| match self.executions(address) {
+                0 => "-".to_owned(),
+                _ => n.to_string(),
+            };
+            let branch = self.branch(address).unwrap_or_default();
+            writeln!(out, "BRDA:{},{},0,{}", line, block, count(branch.taken))?;
+            writeln!(out, "BRDA:{},{},1,{}", line, block, count(branch.not_taken))?;
+            found += 2;
+            hit += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+        }
+        writeln!(out, "BRF:{}\nBRH:{}", found, hit)?;
+
+        for &(address, line) in &instructions {
+            writeln!(out, "DA:{},{}", line, self.executions(address))?;
+        }
+        let hit = instructions
+            .iter()
+            .filter(|&&(address, _)| self.executions(address) > 0)
+            .count();
+        writeln!(out, "LF:{}\nLH:{}", instructions.len(), hit)?;
+        writeln!(out, "end_of_record")
+    }
+
+    pub fn summary(&self, assembly: &Assembly) -> Summary {
+        let instructions = instructions(assembly);
+        let executed = |address| self.executions(address) > 0;
+
+        let mut branches = 0;
+        let mut branches_hit = 0;
+        for &(address, _) in &instructions {
+            if is_branch(assembly, address) {
+                let branch = self.branch(address).unwrap_or_default();
+                branches += 2;
+                branches_hit += (branch.taken > 0) as usize + (branch.not_taken > 0) as usize;
+            }
+        }
+
+        // A label covers the instructions up to the next one.
+        let symbols = assembly.symbols.qualified();
+        let mut uncovered = Vec::new();
+        for (i, &(ref name, symbol)) in symbols.iter().enumerate() {
+            let end = symbols.get(i + 1).map(|&(_, next)| next.address);
+            let covered: Vec<_> = instructions
+                .iter()
+                .filter(|&&(address, _)| {
+                    address >= symbol.address && end.is_none_or(|end| address < end)
+                })
+                .map(|&(address, _)| executed(address))
+                .collect();
+            if !covered.is_empty() && !covered.contains(&true) {
+                uncovered.push(name.clone());
+            }
+        }
+
+        Summary {
+            lines: instructions.len(),
+            lines_hit: instructions
+                .iter()
+                .filter(|&&(address, _)| executed(address))
+                .count(),
+            branches,
+            branches_hit,
+            uncovered,
+        }
+    }
+}
+
+/// The totals of a coverage report.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Summary {
+    pub lines: usize,
+    pub lines_hit: usize,
+    // Each conditional jump has two, taken and not taken.
+    pub branches: usize,
+    pub branches_hit: usize,
+    // Labels and sub-labels that have instructions, none of which were executed.
+    pub uncovered: Vec<String>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let percent = |hit, all| match all {
+            0 => 100.0,
+            _ => hit as f64 * 100.0 / all as f64,
+        };
+        writeln!(
+            f,
+            "lines     {}/{} ({:.1}%)",
+            self.lines_hit,
+            self.lines,
+            percent(self.lines_hit, self.lines)
+        )?;
+        writeln!(
+            f,
+            "branches  {}/{} ({:.1}%)",
+            self.branches_hit,
+            self.branches,
+            percent(self.branches_hit, self.branches)
+        )?;
+        if self.uncovered.is_empty() {
+            writeln!(f, "every label was reached")
+        } else {
+            writeln!(f, "uncovered labels: {}", self.uncovered.join(", "))
+        }
+    }
+}
+
+// The address and 1-based source line of every instruction.
+fn instructions(assembly: &Assembly) -> Vec<(u16, usize)> {
+    assembly
+        .source_map
+        .iter()
+        .filter(|mapping| mapping.kind == MappingKind::Instruction)
+        .map(|mapping| (mapping.address, mapping.pos.line + 1))
+        .collect()
+}
+
+fn is_branch(assembly: &Assembly, address: u16) -> bool {
+    let offset = address.wrapping_sub(assembly.origin) as usize;
+    let mut bytes = assembly.binary.iter().skip(offset).cloned();
+    let instruction = bytes
+        .next()
+        .map(|first| Instruction::disassemble(first, &mut bytes));
+    matches!(
+        instruction,
+        Some(Ok(Instruction::Jg(_))) | Some(Ok(Instruction::Je(_))) | Some(Ok(Instruction::Jl(_)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "main:
+    mov 0x300, 3
+.loop:
+    sub 0x300, 1
+    cmp 0x300, 0
+    je .done
+    jg .loop
+.never:
+    mov 0x302, 1
+.done:
+    int 0x12
+";
+
+    fn run() -> (Assembly, Coverage) {
+        let assembly = assembler::assemble(SOURCE, 0x200).unwrap();
+        let mut m = Machine::new();
+        m.set_host(emulator::Services::new(io::empty(), io::sink()));
+        m.load(0x200, &assembly.binary);
+        let mut coverage = Coverage::new();
+        coverage.run(&mut m, 100).unwrap();
+        assert!(m.halted());
+        (assembly, coverage)
+    }
+
+    #[test]
+    fn test_branches() {
+        let (assembly, coverage) = run();
+        let address = |name| assembly.symbols.resolve(name, None).unwrap().address;
+        let loop_start = address("main.loop");
+        assert_eq!(coverage.executions(loop_start), 3);
+        assert_eq!(coverage.executions(address("main.never")), 0);
+
+        // The je, then the jg.
+        let jumps: Vec<_> = coverage.branches.iter().map(|(_, &b)| b).collect();
+        assert_eq!(
+            jumps,
+            vec![
+                Branch {
+                    taken: 1,
+                    not_taken: 2,
+                },
+                Branch {
+                    taken: 2,
+                    not_taken: 0,
+                },
+            ]
+        );
+
+        let summary = coverage.summary(&assembly);
+        assert_eq!(
+            summary,
+            Summary {
+                lines: 7,
+                lines_hit: 6,
+                branches: 4,
+                branches_hit: 3,
+                uncovered: vec!["main.never".to_owned()],
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "lines     6/7 (85.7%)\nbranches  3/4 (75.0%)\nuncovered labels: main.never\n"
+        );
+    }
+
+    #[test]
+    fn test_lcov() {
+        let (assembly, coverage) = run();
+        let mut lcov = Vec::new();
+        coverage
+            .write_lcov(&assembly, "loop.asm", &mut lcov)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "TN:
+SF:loop.asm
+FN:2,main
+FNDA:1,main
+FNF:1
+FNH:1
+BRDA:6,0,0,1
+BRDA:6,0,1,2
+BRDA:7,1,0,2
+BRDA:7,1,1,0
+BRF:4
+BRH:3
+DA:2,1
+DA:4,3
+DA:5,3
+DA:6,3
+DA:7,2
+DA:9,0
+DA:11,1
+LF:7
+LH:6
+end_of_record
+"
+        );
+    }
+}
@@ -0,0 +1,671 @@
+use super::*;
+
+use emulator::Flags;
+use Unit;
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.empu.core">
+    <flags id="empu_flags" size="2">
+      <field name="equal" start="0" end="0"/>
+      <field name="greater" start="1" end="1"/>
+      <field name="less" start="2" end="2"/>
+      <field name="carry" start="3" end="3"/>
+      <field name="overflow" start="4" end="4"/>
+    </flags>
+    <reg name="ip" bitsize="16" type="code_ptr" regnum="0"/>
+    <reg name="flags" bitsize="16" type="empu_flags" regnum="1"/>
+  </feature>
+</target>
+"#;
+
+// The signals stop replies report.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGABRT: u8 = 6;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+// How many instructions `continue` runs between looks for an interrupt from the client.
+const SLICE: u64 = 10_000;
+
+/// A stream a client connects through. Reads have to be able to stop blocking, so a running
+/// program can be interrupted.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+// A watchpoint set through a `Z` packet: its type, address, length and what it was added as.
+struct Watch {
+    kind: u8,
+    address: u16,
+    length: u16,
+    ids: Vec<WatchpointId>,
+}
+
+/// A GDB remote serial protocol server for a debugger.
+///
+/// Besides the basics, it supports software and hardware breakpoints (which are the same),
+/// write, read and access watchpoints, `vCont`, no-ack mode and a target description with the
+/// registers `ip` and `flags`, both 16 bits and big-endian like the machine. `flags` holds
+/// `Flags::bits`. Faults stop the machine with a signal that fits them, like `SIGFPE` for a
+/// division by zero. A client can interrupt `continue` with Ctrl-C.
+pub struct GdbServer<'a> {
+    debugger: &'a mut Debugger,
+    // How many instructions `continue` runs at most before it stops like an interrupt.
+    max_steps: u64,
+    ack: bool,
+    swbreak: bool,
+    watches: Vec<Watch>,
+    stop: Vec<u8>,
+    done: bool,
+    input: Vec<u8>,
+    position: usize,
+}
+
+impl<'a> GdbServer<'a> {
+    pub fn new(debugger: &'a mut Debugger, max_steps: u64) -> Self {
+        GdbServer {
+            debugger,
+            max_steps,
+            ack: true,
+            swbreak: false,
+            watches: Vec::new(),
+            stop: format!("S{:02x}", SIGTRAP).into_bytes(),
+            done: false,
+            input: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Serves one client until it detaches, kills the program or disconnects.
+    pub fn serve<S: Connection>(&mut self, mut stream: S) -> io::Result<()> {
+        while !self.done {
+            match self.byte(&mut stream)? {
+                Some(b'$') => {}
+                // A stopped machine can't be interrupted any further.
+                Some(0x03) => {
+                    let reply = format!("S{:02x}", SIGINT).into_bytes();
+                    send(&mut stream, &reply)?;
+                    continue;
+                }
+                // Acknowledgements and garbage.
+                Some(_) => continue,
+                None => return Ok(()),
+            }
+            let mut packet = Vec::new();
+            loop {
+                match self.byte(&mut stream)? {
+                    Some(b'#') => break,
+                    Some(byte) => packet.push(byte),
+                    None => return Ok(()),
+                }
+            }
+            let mut checksum = [0; 2];
+            for digit in &mut checksum {
+                *digit = self.byte(&mut stream)?.unwrap_or(0);
+            }
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                == Some(sum(&packet));
+            if self.ack {
+                stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                if let Some(reply) = self.handle(&mut stream, &packet)? {
+                    send(&mut stream, &reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn byte<S: Read>(&mut self, stream: &mut S) -> io::Result<Option<u8>> {
+        if self.position == self.input.len() {
+            self.input.resize(4096, 0);
+            let n = stream.read(&mut self.input)?;
+            self.input.truncate(n);
+            self.position = 0;
+            if n == 0 {
+                return Ok(None);
+            }
+        }
+        self.position += 1;
+        Ok(Some(self.input[self.position - 1]))
+    }
+
+    /// The reply to `packet`, if it gets one.
+    fn handle<S: Connection>(
+        &mut self,
+        stream: &mut S,
+        packet: &[u8],
+    ) -> io::Result<Option<Vec<u8>>> {
+        let text = String::from_utf8_lossy(packet).into_owned();
+        let reply = match packet.first() {
+            Some(b'?') => return Ok(Some(self.stop.clone())),
+            Some(b'g') => format!(
+                "{:04x}{:04x}",
+                self.debugger.machine().ip(),
+                self.debugger.machine().flags().bits()
+            ),
+            Some(b'G') => self.write_registers(&text[1..]),
+            Some(b'p') => match u8::from_str_radix(&text[1..], 16) {
+                Ok(0) => format!("{:04x}", self.debugger.machine().ip()),
+                Ok(1) => format!("{:04x}", self.debugger.machine().flags().bits()),
+                _ => "E00".to_owned(),
+            },
+            Some(b'P') => self.write_register(&text[1..]),
+            Some(b'm') => self.read_memory(&text[1..]),
+            Some(b'M') => {
+                let data = text
+                    .find(':')
+                    .and_then(|colon| hex_bytes(&text[colon + 1..]));
+                self.write_memory(&text[1..], data)
+            }
+            Some(b'X') => {
+                let data = packet
+                    .iter()
+                    .position(|&b| b == b':')
+                    .map(|colon| unescape(&packet[colon + 1..]));
+                self.write_memory(&text[1..], data)
+            }
+            Some(b's') | Some(b'c') => {
+                if text.len() > 1 {
+                    match parse_u16(&text[1..]) {
+                        Some(address) => self.debugger.machine_mut().set_ip(address),
+                        None => return Ok(Some(b"E00".to_vec())),
+                    }
+                }
+                return Ok(Some(self.resume(stream, packet[0] == b's')?));
+            }
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet[0] == b'Z', &text[1..]),
+            Some(b'H') | Some(b'T') => "OK".to_owned(),
+            Some(b'D') => {
+                self.done = true;
+                "OK".to_owned()
+            }
+            Some(b'k') => {
+                self.done = true;
+                return Ok(None);
+            }
+            _ if text == "vCont?" => "vCont;c;s".to_owned(),
+            _ if text.starts_with("vCont;") => {
+                // There is only one thread, so the first action applies.
+                match text[6..].split(&[';', ':'][..]).next() {
+                    Some("s") => return Ok(Some(self.resume(stream, true)?)),
+                    Some("c") => return Ok(Some(self.resume(stream, false)?)),
+                    _ => "E00".to_owned(),
+                }
+            }
+            _ if text.starts_with("qSupported") => {
+                self.swbreak = text.contains("swbreak+");
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+".to_owned()
+            }
+            _ if text == "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_owned()
+            }
+            _ if text.starts_with("qXfer:features:read:target.xml:") => {
+                return Ok(Some(target_xml(
+                    &text["qXfer:features:read:target.xml:".len()..],
+                )));
+            }
+            _ if text == "qAttached" => "1".to_owned(),
+            _ if text == "qC" => "QC1".to_owned(),
+            _ if text == "qfThreadInfo" => "m1".to_owned(),
+            _ if text == "qsThreadInfo" => "l".to_owned(),
+            _ => String::new(),
+        };
+        Ok(Some(reply.into_bytes()))
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        if hex.len() < 8 {
+            return "E00".to_owned();
+        }
+        match (parse_u16(&hex[..4]), parse_u16(&hex[4..8])) {
+            (Some(ip), Some(flags)) => {
+                let machine = self.debugger.machine_mut();
+                machine.set_ip(ip);
+                machine.set_flags(Flags::from_bits(flags as u8));
+                "OK".to_owned()
+            }
+            _ => "E00".to_owned(),
+        }
+    }
+
+    fn write_register(&mut self, assignment: &str) -> String {
+        let mut parts = assignment.splitn(2, '=');
+        let register = parts.next().and_then(|n| u8::from_str_radix(n, 16).ok());
+        let value = parts.next().and_then(parse_u16);
+        let machine = self.debugger.machine_mut();
+        match (register, value) {
+            (Some(0), Some(ip)) => machine.set_ip(ip),
+            (Some(1), Some(flags)) => machine.set_flags(Flags::from_bits(flags as u8)),
+            _ => return "E00".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    fn read_memory(&self, range: &str) -> String {
+        let (address, length) = match parse_range(range) {
+            Some(range) => range,
+            None => return "E00".to_owned(),
+        };
+        let machine = self.debugger.machine();
+        let addresses = (0..length).map(|i| address.wrapping_add(i));
+        if !addresses.clone().all(|a| machine.bus().is_mapped(a)) {
+            return "E14".to_owned();
+        }
+        addresses
+            .map(|a| format!("{:02x}", machine.peek(a, Unit::Byte)))
+            .collect()
+    }
+
+    fn write_memory(&mut self, range: &str, data: Option<Vec<u8>>) -> String {
+        let range = range.split(':').next().and_then(parse_range);
+        let (address, data) = match (range, data) {
+            (Some((address, length)), Some(data)) if data.len() == length as usize => {
+                (address, data)
+            }
+            _ => return "E00".to_owned(),
+        };
+        let machine = self.debugger.machine_mut();
+        let addresses = (0..data.len() as u16).map(|i| address.wrapping_add(i));
+        if !addresses.clone().all(|a| machine.bus().is_mapped(a)) {
+            return "E14".to_owned();
+        }
+        for (a, &byte) in addresses.zip(&data) {
+            machine.write(a, Unit::Byte, byte as u32);
+        }
+        "OK".to_owned()
+    }
+
+    fn breakpoint(&mut self, insert: bool, spec: &str) -> String {
+        let mut fields = spec.split(',');
+        let kind = fields.next().and_then(|k| k.parse::<u8>().ok());
+        let address = fields.next().and_then(parse_u16);
+        let length = fields.next().and_then(parse_u16).unwrap_or(1).max(1);
+        let (kind, address) = match (kind, address) {
+            (Some(kind), Some(address)) => (kind, address),
+            _ => return "E00".to_owned(),
+        };
+        match (kind, insert) {
+            (0, true) | (1, true) => {
+                self.debugger.add_breakpoint(address);
+            }
+            (0, false) | (1, false) => {
+                self.debugger.remove_breakpoint(address);
+            }
+            (2..=4, true) => {
+                let range = address..=address.saturating_add(length - 1);
+                let kinds: &[WatchKind] = match kind {
+                    2 => &[WatchKind::Write],
+                    3 => &[WatchKind::Read],
+                    _ => &[WatchKind::Read, WatchKind::Write],
+                };
+                let ids = kinds
+                    .iter()
+                    .map(|&k| {
+                        self.debugger
+                            .add_watchpoint(Watchpoint::new(range.clone(), k))
+                    })
+                    .collect();
+                self.watches.push(Watch {
+                    kind,
+                    address,
+                    length,
+                    ids,
+                });
+            }
+            (2..=4, false) => {
+                let i = self.watches.iter().position(|watch| {
+                    (watch.kind, watch.address, watch.length) == (kind, address, length)
+                });
+                match i {
+                    Some(i) => {
+                        for id in self.watches.remove(i).ids {
+                            self.debugger.remove_watchpoint(id);
+                        }
+                    }
+                    None => return "E00".to_owned(),
+                }
+            }
+            // Other kinds aren't supported.
+            _ => return String::new(),
+        }
+        "OK".to_owned()
+    }
+
+    /// Steps or continues, and returns the stop reply.
+    fn resume<S: Connection>(&mut self, stream: &mut S, step: bool) -> io::Result<Vec<u8>> {
+        let res = if step {
+            self.debugger.run_until(1, |_, _| true)
+        } else {
+            let mut left = self.max_steps;
+            loop {
+                let slice = left.min(SLICE);
+                left -= slice;
+                match self.debugger.cont(slice) {
+                    Ok(Stop::StepLimit) if left > 0 && !self.interrupted(stream)? => {}
+                    res => break res,
+                }
+            }
+        };
+        let reply = match res {
+            Ok(Stop::Halted) => "W00".to_owned(),
+            Ok(Stop::Breakpoint(_)) if self.swbreak => format!("T{:02x}swbreak:;", SIGTRAP),
+            Ok(Stop::Watchpoint(hit)) => {
+                let kind = self
+                    .watches
+                    .iter()
+                    .find(|watch| watch.ids.contains(&hit.watchpoint))
+                    .map_or(2, |watch| watch.kind);
+                let name = match kind {
+                    2 => "watch",
+                    3 => "rwatch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, name, hit.access)
+            }
+            Ok(Stop::StepLimit) if !step => format!("T{:02x}", SIGINT),
+            Ok(_) => format!("T{:02x}", SIGTRAP),
+            Err(fault) => {
+                let signal = match fault {
+                    Fault::InvalidInstruction(_) | Fault::TruncatedInstruction(_) => SIGILL,
+                    Fault::DivisionByZero(_) => SIGFPE,
+                    Fault::UnmappedAccess(..) => SIGSEGV,
+                    _ => SIGABRT,
+                };
+                format!("T{:02x}", signal)
+            }
+        };
+        self.stop = reply.into_bytes();
+        Ok(self.stop.clone())
+    }
+
+    /// Whether the client sent an interrupt or went away, without waiting for it.
+    fn interrupted<S: Connection>(&mut self, stream: &mut S) -> io::Result<bool> {
+        let mut buffer = [0; 256];
+        stream.set_nonblocking(true)?;
+        let res = stream.read(&mut buffer);
+        stream.set_nonblocking(false)?;
+        let n = match res {
+            Ok(0) => return Ok(true),
+            Ok(n) => n,
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(err) => return Err(err),
+        };
+        // Anything else stays for `serve`.
+        self.input.drain(..self.position);
+        self.position = 0;
+        self.input.extend_from_slice(&buffer[..n]);
+        match self.input.iter().position(|&b| b == 0x03) {
+            Some(i) => {
+                self.input.remove(i);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+fn sum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum: u8, &b| sum.wrapping_add(b))
+}
+
+fn send<W: Write>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &b in data {
+        match b {
+            b'#' | b'$' | b'}' | b'*' => escaped.extend_from_slice(&[b'}', b ^ 0x20]),
+            _ => escaped.push(b),
+        }
+    }
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", sum(&escaped)).as_bytes());
+    out.write_all(&packet)?;
+    out.flush()
+}
+
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(data.len());
+    let mut escaped = false;
+    for &b in data {
+        match b {
+            b'}' if !escaped => escaped = true,
+            _ if escaped => {
+                bytes.push(b ^ 0x20);
+                escaped = false;
+            }
+            _ => bytes.push(b),
+        }
+    }
+    bytes
+}
+
+// `OFFSET,LENGTH` of the target description.
+fn target_xml(window: &str) -> Vec<u8> {
+    let (offset, length) = match parse_range(window) {
+        Some((offset, length)) => (offset as usize, length as usize),
+        None => return b"E00".to_vec(),
+    };
+    let xml = TARGET_XML.as_bytes();
+    let start = offset.min(xml.len());
+    let end = (start + length).min(xml.len());
+    let mut reply = vec![if end == xml.len() { b'l' } else { b'm' }];
+    reply.extend_from_slice(&xml[start..end]);
+    reply
+}
+
+fn parse_u16(hex: &str) -> Option<u16> {
+    u16::from_str_radix(hex, 16).ok()
+}
+
+// `ADDRESS,LENGTH`, in hex.
+fn parse_range(range: &str) -> Option<(u16, u16)> {
+    let mut parts = range.splitn(2, ',');
+    Some((parse_u16(parts.next()?)?, parse_u16(parts.next()?)?))
+}
+
+fn hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    struct Client {
+        stream: TcpStream,
+        ack: bool,
+    }
+
+    impl Client {
+        fn request(&mut self, packet: &str) -> String {
+            write!(self.stream, "${}#{:02x}", packet, sum(packet.as_bytes())).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut reply = Vec::new();
+            let mut byte = [0];
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                match byte[0] {
+                    b'+' if self.ack && reply.is_empty() => {}
+                    b'$' => reply.clear(),
+                    b'#' => break,
+                    b => reply.push(b),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(
+                u8::from_str_radix(std::str::from_utf8(&checksum).unwrap(), 16).unwrap(),
+                sum(&reply)
+            );
+            if self.ack {
+                self.stream.write_all(b"+").unwrap();
+            }
+            String::from_utf8(unescape(&reply)).unwrap()
+        }
+    }
+
+    fn debugger() -> Debugger {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0x300, 1
+.loop:
+    add 0x300, 1
+    cmp 0x300, 3
+    jl .loop
+    div 0x300, 0
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        Debugger::new(m, assembly.symbols)
+    }
+
+    #[test]
+    fn test_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut client = Client { stream, ack: true };
+            let mut replies = Vec::new();
+            let mut request = |client: &mut Client, packet: &str| {
+                replies.push(client.request(packet));
+            };
+            request(&mut client, "qSupported:multiprocess+;swbreak+;hwbreak+");
+            request(&mut client, "QStartNoAckMode");
+            client.ack = false;
+            request(&mut client, "qXfer:features:read:target.xml:0,40");
+            request(&mut client, "?");
+            request(&mut client, "g");
+            request(&mut client, "m200,6");
+            request(&mut client, "s");
+            request(&mut client, "p0");
+            // A breakpoint on the cmp.
+            request(&mut client, "Z0,20c,1");
+            request(&mut client, "c");
+            request(&mut client, "z0,20c,1");
+            request(&mut client, "Z2,300,2");
+            request(&mut client, "vCont;c");
+            request(&mut client, "z2,300,2");
+            request(&mut client, "M310,2:beef");
+            request(&mut client, "X312,2:}]}\x03");
+            request(&mut client, "m310,4");
+            request(&mut client, "P1=3");
+            request(&mut client, "g");
+            request(&mut client, "c");
+            request(&mut client, "qUnknown");
+            request(&mut client, "D");
+            replies
+        });
+
+        let mut d = debugger();
+        let stream = listener.accept().unwrap().0;
+        stream.set_nodelay(true).unwrap();
+        GdbServer::new(&mut d, 1000).serve(stream).unwrap();
+        let replies = client.join().unwrap();
+        assert_eq!(
+            replies,
+            vec![
+                "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+",
+                "OK",
+                &format!("m{}", &TARGET_XML[..0x40]),
+                "S05",
+                "02000000",
+                "050003000001",
+                "T05",
+                "0206",
+                "OK",
+                "T05swbreak:;",
+                "OK",
+                "OK",
+                "T05watch:0300;",
+                "OK",
+                "OK",
+                "OK",
+                "beef7d23",
+                "OK",
+                "020c0003",
+                "T08",
+                "",
+                "OK",
+            ]
+        );
+        assert_eq!(d.machine().peek(0x310, Unit::Dword), 0xBEEF_7D23);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // The server runs on its own thread, so the test fails instead of hanging if the
+        // interrupt is missed.
+        let server = thread::spawn(move || {
+            let assembly = assembler::assemble("main:\n    jmp main\n", 0x200).unwrap();
+            let mut m = Machine::new();
+            m.load(0x200, &assembly.binary);
+            let mut d = Debugger::new(m, assembly.symbols);
+            let stream = listener.accept().unwrap().0;
+            GdbServer::new(&mut d, u64::MAX).serve(stream).unwrap();
+            d.machine().ip()
+        });
+
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut client = Client { stream, ack: true };
+        write!(client.stream, "$c#{:02x}", sum(b"c")).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.stream.write_all(&[0x03]).unwrap();
+        assert_eq!(client.reply(), "T02");
+        assert_eq!(client.request("p0"), "0200");
+        assert_eq!(client.request("D"), "OK");
+        assert_eq!(server.join().unwrap(), 0x200);
+    }
+}
@@ -0,0 +1,467 @@
+use super::*;
+
+use emulator::{CpuState, Flags, Frame, MemoryWrite};
+use Instruction;
+
+use std::collections::VecDeque;
+use std::ops::RangeInclusive;
+
+// The registers before a step, how it changed the interrupt frames, and what it wrote. The
+// frames and the queue of device interrupts usually take no allocation.
+struct Record {
+    ip: u16,
+    flags: Flags,
+    halted: bool,
+    steps: u64,
+    cycles: u64,
+    pending: Vec<u8>,
+    // The frame `iret` popped, then the frames of the interrupts entered.
+    popped: Option<Frame>,
+    pushed: Vec<Frame>,
+    writes: Vec<MemoryWrite>,
+}
+
+impl Record {
+    // Puts the registers back to before the step, leaving the frames alone.
+    fn registers(&self, state: &mut CpuState) {
+        state.ip = self.ip;
+        state.flags = self.flags;
+        state.halted = self.halted;
+        state.steps = self.steps;
+        state.cycles = self.cycles;
+        state.pending = self.pending.clone();
+    }
+}
+
+struct Checkpoint {
+    state: CpuState,
+    ram: Vec<u8>,
+}
+
+// What to record about a step that is about to execute.
+pub(super) struct Pending {
+    record: Record,
+    depth: usize,
+    last: Option<Frame>,
+    ram: Option<Vec<u8>>,
+}
+
+/// The recorded past of a machine, which the debugger can go back to.
+///
+/// Every step is kept in an undo log, and every `interval` steps a checkpoint copies all of RAM.
+/// Only the newest `checkpoints` checkpoints and the steps since the oldest of them are kept,
+/// which bounds the memory used. Devices can't be rewound, so writes to them aren't undone.
+pub struct History {
+    interval: u64,
+    max_checkpoints: usize,
+    checkpoints: VecDeque<Checkpoint>,
+    // Starts at the oldest checkpoint.
+    records: VecDeque<Record>,
+    // How many records are applied, less than all of them while the machine is in the past.
+    position: usize,
+    // The state after the last record, while the machine is in the past.
+    present: Option<CpuState>,
+}
+
+impl History {
+    pub fn new(interval: u64, checkpoints: usize) -> Self {
+        History {
+            interval: interval.max(1),
+            max_checkpoints: checkpoints.max(1),
+            checkpoints: VecDeque::new(),
+            records: VecDeque::new(),
+            position: 0,
+            present: None,
+        }
+    }
+
+    /// The step counts the machine can go to, if anything was recorded.
+    pub fn steps(&self) -> Option<RangeInclusive<u64>> {
+        self.records
+            .front()
+            .map(|first| first.steps..=first.steps + self.records.len() as u64)
+    }
+
+    /// Whether the machine is somewhere in the past.
+    pub fn in_past(&self) -> bool {
+        self.position < self.records.len()
+    }
+
+    pub(super) fn prepare(&mut self, machine: &Machine) -> Pending {
+        let checkpoint = (self.position as u64).is_multiple_of(self.interval);
+        let frames = machine.interrupts();
+        Pending {
+            record: Record {
+                ip: machine.ip(),
+                flags: machine.flags(),
+                halted: machine.halted(),
+                steps: machine.steps(),
+                cycles: machine.cycles(),
+                pending: machine.pending_interrupts().iter().cloned().collect(),
+                popped: None,
+                pushed: Vec::new(),
+                writes: Vec::new(),
+            },
+            depth: frames.len(),
+            last: frames.last().cloned(),
+            ram: if checkpoint {
+                Some(machine.memory().to_vec())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// Records a step that executed. `machine` is the state after it.
+    pub(super) fn push(&mut self, pending: Pending, step: &Step, machine: &Machine) {
+        // Executing from the past starts a new future. A checkpoint at the current step is
+        // taken again below.
+        let mut record = pending.record;
+        self.records.truncate(self.position);
+        self.checkpoints.retain(|c| c.state.steps < record.steps);
+        self.present = None;
+
+        if step.instruction == Some(Instruction::Iret) && step.exception.is_none() {
+            record.popped = pending.last;
+        }
+        let depth = pending.depth - record.popped.is_some() as usize;
+        record.pushed = machine.interrupts()[depth..].to_vec();
+        record.writes = step.writes.clone();
+
+        if let Some(ram) = pending.ram {
+            let mut state = machine.cpu_state();
+            record.registers(&mut state);
+            state.frames.truncate(depth);
+            state.frames.extend(record.popped.clone());
+            self.checkpoints.push_back(Checkpoint { state, ram });
+            if self.checkpoints.len() > self.max_checkpoints {
+                self.checkpoints.pop_front();
+                let n = self.interval.min(self.records.len() as u64) as usize;
+                self.records.drain(..n);
+            }
+        }
+        self.records.push_back(record);
+        self.position = self.records.len();
+    }
+
+    /// Undoes the last applied step and returns what it wrote.
+    pub(super) fn back(&mut self, machine: &mut Machine) -> Option<&[MemoryWrite]> {
+        if self.position == 0 {
+            return None;
+        }
+        let mut state = machine.cpu_state();
+        if !self.in_past() {
+            self.present = Some(state.clone());
+        }
+        self.position -= 1;
+        let record = &self.records[self.position];
+        for write in record.writes.iter().rev() {
+            restore(machine, write, write.old);
+        }
+        record.registers(&mut state);
+        let depth = state.frames.len() - record.pushed.len();
+        state.frames.truncate(depth);
+        state.frames.extend(record.popped.clone());
+        machine.set_cpu_state(state);
+        Some(&record.writes)
+    }
+
+    /// Redoes the next step, without executing it.
+    pub(super) fn forward(&mut self, machine: &mut Machine) -> bool {
+        if !self.in_past() {
+            return false;
+        }
+        let record = &self.records[self.position];
+        for write in &record.writes {
+            restore(machine, write, write.new);
+        }
+        self.position += 1;
+        let state = match self.records.get(self.position) {
+            Some(next) => {
+                let mut state = machine.cpu_state();
+                next.registers(&mut state);
+                if record.popped.is_some() {
+                    state.frames.pop();
+                }
+                state.frames.extend(record.pushed.iter().cloned());
+                state
+            }
+            None => self.present.take().unwrap(),
+        };
+        machine.set_cpu_state(state);
+        true
+    }
+
+    pub(super) fn goto(&mut self, machine: &mut Machine, steps: u64) -> bool {
+        let first = match self.steps() {
+            Some(ref range) if range.contains(&steps) => *range.start(),
+            _ => return false,
+        };
+        let current = machine.steps();
+        let distance = current.max(steps) - current.min(steps);
+        let checkpoint = self
+            .checkpoints
+            .iter()
+            .rev()
+            .find(|c| c.state.steps <= steps);
+        let checkpoint = match checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return false,
+        };
+        if steps - checkpoint.state.steps < distance {
+            if !self.in_past() {
+                self.present = Some(machine.cpu_state());
+            }
+            machine.memory_mut().copy_from_slice(&checkpoint.ram);
+            machine.set_cpu_state(checkpoint.state.clone());
+            self.position = (checkpoint.state.steps - first) as usize;
+        }
+        while machine.steps() > steps {
+            self.back(machine);
+        }
+        while machine.steps() < steps {
+            self.forward(machine);
+        }
+        true
+    }
+}
+
+// Puts `value` back into RAM, unless the write went to a device.
+fn restore(machine: &mut Machine, write: &MemoryWrite, value: u32) {
+    if machine.bus().device_at(write.address).is_some() {
+        return;
+    }
+    let n = write.unit.num_bytes() as u16;
+    let ram = machine.memory_mut();
+    for i in 0..n {
+        ram[write.address.wrapping_add(i) as usize] = (value >> ((n - 1 - i) * 8)) as u8;
+    }
+}
+
+impl Debugger {
+    /// Starts recording every step from now on, see `History`.
+    pub fn record_history(&mut self, interval: u64, checkpoints: usize) {
+        self.history = Some(History::new(interval, checkpoints));
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Goes back one step. Returns whether there was a recorded step to go back to.
+    pub fn step_back(&mut self) -> bool {
+        match self.history {
+            Some(ref mut history) => history.back(&mut self.machine).is_some(),
+            None => false,
+        }
+    }
+
+    /// Goes back until right before an instruction that triggered a write or change watchpoint,
+    /// or that has a breakpoint.
+    pub fn reverse_cont(&mut self) -> Stop {
+        loop {
+            let writes = match self.history {
+                Some(ref mut history) => match history.back(&mut self.machine) {
+                    Some(writes) => writes.to_vec(),
+                    None => return Stop::HistoryStart,
+                },
+                None => return Stop::HistoryStart,
+            };
+            let ip = self.machine.ip();
+            let step = Step {
+                address: ip,
+                length: 0,
+                instruction: self.machine.fetch().ok().map(|(ins, _)| ins),
+                effective: Default::default(),
+                reads: Vec::new(),
+                writes,
+                exception: None,
+                cycles: 0,
+            };
+            if let Some(hit) = self.watch_hits(&step).into_iter().next() {
+                return Stop::Watchpoint(hit);
+            } else if self.breakpoints.contains(&ip) {
+                return Stop::Breakpoint(ip);
+            }
+        }
+    }
+
+    /// Goes to the point where the machine had executed `steps` instructions, backwards or
+    /// forwards. Returns whether that point is recorded.
+    pub fn goto(&mut self, steps: u64) -> bool {
+        match self.history {
+            Some(ref mut history) => history.goto(&mut self.machine, steps),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+    use Unit;
+
+    // The low byte of the add's immediate.
+    const PATCH: u16 = 0x205;
+
+    // Counts 0x300 up forever, in steps of 2 once it reached 5 and patched its own add.
+    fn debugger() -> Debugger {
+        let assembly = assembler::assemble(
+            "main:
+    add 0x300, 1
+    cmp 0x300, 5
+    jl main
+    mov byte 0x205, 2
+    jmp main
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        Debugger::new(m, assembly.symbols)
+    }
+
+    fn counter(d: &mut Debugger) -> u32 {
+        d.machine_mut().read(0x300, Unit::Word)
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut d = debugger();
+        assert!(!d.step_back());
+        d.record_history(4, 2);
+        assert_eq!(d.cont(20), Ok(Stop::StepLimit));
+        let end = d.machine().cpu_state();
+        let memory = d.machine().memory().to_vec();
+
+        // Only the last 2 checkpoints are kept: the ones at steps 12 and 16.
+        assert_eq!(d.history().unwrap().steps(), Some(12..=20));
+        assert!(d.step_back());
+        assert_eq!(d.machine().steps(), 19);
+        assert!(d.goto(12));
+        assert_eq!(d.machine().steps(), 12);
+        assert!(!d.goto(11));
+        while d.step_back() {}
+        assert_eq!(d.machine().steps(), 12);
+
+        assert!(d.goto(20));
+        assert_eq!(d.machine().cpu_state(), end);
+        assert_eq!(d.machine().memory(), &memory[..]);
+        assert!(!d.history().unwrap().in_past());
+    }
+
+    #[test]
+    fn test_execute_from_checkpoint() {
+        let mut d = debugger();
+        d.record_history(4, 2);
+        d.cont(20).unwrap();
+
+        // The checkpoint at 16 is taken again instead of twice, which would push out the one
+        // at 12 early.
+        assert!(d.goto(16));
+        d.step().unwrap();
+        assert_eq!(d.history().unwrap().steps(), Some(12..=17));
+        d.cont(7).unwrap();
+        assert_eq!(d.history().unwrap().steps(), Some(16..=24));
+        assert!(d.goto(16));
+        assert_eq!(d.machine().steps(), 16);
+    }
+
+    #[test]
+    fn test_fault_in_past() {
+        let mut d = debugger();
+        d.record_history(4, 2);
+        d.cont(20).unwrap();
+        let end = d.machine().cpu_state();
+        let memory = d.machine().memory().to_vec();
+
+        // A fault executes nothing, so the future stays.
+        assert!(d.goto(14));
+        let ip = d.machine().ip();
+        let byte = d.machine().peek(ip, Unit::Byte);
+        d.machine_mut().write(ip, Unit::Byte, 0xF0);
+        assert!(d.step().is_err());
+        d.machine_mut().write(ip, Unit::Byte, byte);
+        assert_eq!(d.history().unwrap().steps(), Some(12..=20));
+        assert!(d.goto(20));
+        assert_eq!(d.machine().cpu_state(), end);
+        assert_eq!(d.machine().memory(), &memory[..]);
+    }
+
+    #[test]
+    fn test_interrupt_frames() {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0xEE, handler
+.loop:
+    int 0xEE
+    jmp .loop
+handler:
+    int 0xEF
+    iret
+inner:
+    iret
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        let inner = assembly.symbols.get("inner").unwrap().address;
+        let vector = m.vector_table().entry(0xEF);
+        m.write(vector, Unit::Word, inner as u32);
+        let mut d = Debugger::new(m, assembly.symbols);
+        d.record_history(3, 4);
+        let mut states = vec![d.machine().cpu_state()];
+        for _ in 0..10 {
+            d.step().unwrap();
+            states.push(d.machine().cpu_state());
+        }
+        assert!(states.iter().any(|state| state.frames.len() == 2));
+
+        for state in states.iter().rev().skip(1) {
+            assert!(d.step_back());
+            assert_eq!(&d.machine().cpu_state(), state);
+        }
+        for (steps, state) in states.iter().enumerate() {
+            assert!(d.goto(steps as u64));
+            assert_eq!(&d.machine().cpu_state(), state);
+        }
+    }
+
+    #[test]
+    fn test_reverse_cont() {
+        let mut d = debugger();
+        d.record_history(8, 4);
+        d.cont(30).unwrap();
+
+        // Back to right before the self-modification. Later, the program only writes the same
+        // value again.
+        d.add_watchpoint(Watchpoint::new(PATCH..=PATCH, WatchKind::Change));
+        match d.reverse_cont() {
+            Stop::Watchpoint(hit) => assert_eq!((hit.old, hit.new), (1, 2)),
+            stop => panic!("{:?}", stop),
+        }
+        assert_eq!(d.machine().steps(), 15);
+        assert_eq!(d.machine().memory()[PATCH as usize], 1);
+        assert_eq!(counter(&mut d), 5);
+
+        d.add_breakpoint(0x206);
+        assert_eq!(d.reverse_cont(), Stop::Breakpoint(0x206));
+        assert_eq!(counter(&mut d), 5);
+        assert_eq!(d.reverse_cont(), Stop::Breakpoint(0x206));
+        assert_eq!(counter(&mut d), 4);
+        d.remove_breakpoint(0x206);
+        assert_eq!(d.reverse_cont(), Stop::HistoryStart);
+        assert_eq!((d.machine().steps(), counter(&mut d)), (0, 0));
+
+        // Executing from the past starts a new future.
+        assert!(d.goto(10));
+        d.step().unwrap();
+        assert_eq!(d.history().unwrap().steps(), Some(0..=11));
+        assert!(!d.goto(30));
+    }
+}
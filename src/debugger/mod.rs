@@ -0,0 +1,271 @@
+use assembler::symbols::SymbolTable;
+use emulator::{Fault, Machine, Step};
+
+use std::collections::BTreeSet;
+
+mod gdb;
+mod history;
+mod watch;
+
+pub use self::gdb::{Connection, GdbServer};
+pub use self::history::History;
+pub use self::watch::{WatchHit, WatchKind, Watchpoint, WatchpointId};
+
+/// Why the debugger handed control back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    // `step_over` is done.
+    Stepped,
+    // The instruction pointer reached a breakpoint. The instruction there hasn't run yet.
+    Breakpoint(u16),
+    // The first watchpoint the last step triggered.
+    Watchpoint(WatchHit),
+    // The predicate of `run_until` held.
+    Condition,
+    Halted,
+    // Going back reached the oldest recorded step.
+    HistoryStart,
+    // The steps allowed ran out.
+    StepLimit,
+}
+
+/// Drives a `Machine` one step at a time, stopping at breakpoints and other conditions.
+pub struct Debugger {
+    machine: Machine,
+    symbols: SymbolTable,
+    breakpoints: BTreeSet<u16>,
+    watchpoints: Vec<(WatchpointId, Watchpoint)>,
+    next_watchpoint: usize,
+    history: Option<History>,
+    last: Option<Step>,
+}
+
+impl Debugger {
+    /// `symbols` resolves labels for breakpoints; pass an empty table if there are none.
+    pub fn new(machine: Machine, symbols: SymbolTable) -> Self {
+        Debugger {
+            machine,
+            symbols,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            next_watchpoint: 0,
+            history: None,
+            last: None,
+        }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Machine {
+        &mut self.machine
+    }
+
+    pub fn into_machine(self) -> Machine {
+        self.machine
+    }
+
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Looks up a label like `main` or `main.loop`.
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.symbols.resolve(name, None).map(|sym| sym.address)
+    }
+
+    /// Returns whether the breakpoint is new.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    /// Sets a breakpoint on a label and returns its address, or `None` if there's no such label.
+    pub fn add_label_breakpoint(&mut self, name: &str) -> Option<u16> {
+        let address = self.label(name)?;
+        self.breakpoints.insert(address);
+        Some(address)
+    }
+
+    /// Returns whether there was a breakpoint.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    /// Every breakpoint, in address order.
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().cloned()
+    }
+
+    /// The step executed last.
+    pub fn last_step(&self) -> Option<&Step> {
+        self.last.as_ref()
+    }
+
+    /// Executes exactly one instruction, ignoring breakpoints and watchpoints. In the past,
+    /// this discards the recorded future.
+    pub fn step(&mut self) -> Result<Step, Fault> {
+        let step = self.execute()?;
+        self.last = Some(step.clone());
+        Ok(step)
+    }
+
+    /// Executes one instruction. If that enters an interrupt handler, whether through `int`,
+    /// a device or an exception, it also runs the handler until it returns.
+    pub fn step_over(&mut self, max_steps: u64) -> Result<Stop, Fault> {
+        let depth = self.machine.interrupts().len();
+        let stop = self.run_until(max_steps, |machine, _| machine.interrupts().len() <= depth)?;
+        Ok(if stop == Stop::Condition {
+            Stop::Stepped
+        } else {
+            stop
+        })
+    }
+
+    /// Runs until a breakpoint is reached, a watchpoint triggers, the machine halts or
+    /// `max_steps` are executed.
+    pub fn cont(&mut self, max_steps: u64) -> Result<Stop, Fault> {
+        self.run_until(max_steps, |_, _| false)
+    }
+
+    /// Like `cont`, but also stops once `done` holds after a step. At least one instruction is
+    /// executed, so this can continue from a breakpoint.
+    pub fn run_until<F>(&mut self, max_steps: u64, mut done: F) -> Result<Stop, Fault>
+    where
+        F: FnMut(&Machine, &Step) -> bool,
+    {
+        if self.machine.halted() {
+            return Ok(Stop::Halted);
+        }
+        for _ in 0..max_steps {
+            let step = self.execute()?;
+            let condition = done(&self.machine, &step);
+            let hit = self.watch_hits(&step).into_iter().next();
+            self.last = Some(step);
+            let ip = self.machine.ip();
+            if let Some(hit) = hit {
+                return Ok(Stop::Watchpoint(hit));
+            } else if condition {
+                return Ok(Stop::Condition);
+            } else if self.machine.halted() {
+                return Ok(Stop::Halted);
+            } else if self.breakpoints.contains(&ip) {
+                return Ok(Stop::Breakpoint(ip));
+            }
+        }
+        Ok(Stop::StepLimit)
+    }
+
+    fn execute(&mut self) -> Result<Step, Fault> {
+        let machine = &self.machine;
+        let pending = self
+            .history
+            .as_mut()
+            .map(|history| history.prepare(machine));
+        let step = self.machine.step()?;
+        if let (Some(history), Some(pending)) = (self.history.as_mut(), pending) {
+            history.push(pending, &step, &self.machine);
+        }
+        Ok(step)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+    use emulator::{EffectiveAddresses, HostAction};
+    use {Instruction, Unit};
+
+    fn debugger() -> Debugger {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0xEE, handler
+    mov .ptr, 0x300
+    mov @.ptr, 0xBEEF
+    int 0xEE
+.done:
+    add 0x300, 1
+    int 0x12
+.ptr:
+    db 2
+handler:
+    mov byte 0x302, @.seven
+    iret
+.seven:
+    db 1 7
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        m.on_interrupt(0x12, |_| HostAction::Halt);
+        Debugger::new(m, assembly.symbols)
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut d = debugger();
+        let (handler, ptr) = (d.label("handler").unwrap(), d.label("main.ptr").unwrap());
+
+        let step = d.step().unwrap();
+        assert_eq!(step.address, 0x200);
+        assert_eq!(
+            step.writes[0].bytes(),
+            vec![(handler >> 8) as u8, handler as u8]
+        );
+        d.step().unwrap();
+        let step = d.step().unwrap();
+        match step.instruction {
+            Some(Instruction::Mov(ref usd)) => assert_eq!(usd.destination.location, ptr),
+            ref ins => panic!("{:?}", ins),
+        }
+        assert_eq!(
+            step.effective,
+            EffectiveAddresses {
+                destination: Some(0x300),
+                source: None,
+            }
+        );
+        assert_eq!((step.writes[0].old, step.writes[0].new), (0, 0xBEEF));
+        assert_eq!(step.writes[0].bytes(), vec![0xBE, 0xEF]);
+
+        // Stepping over the int runs the whole handler.
+        assert_eq!(d.step_over(100), Ok(Stop::Stepped));
+        assert_eq!(d.machine().ip(), d.label("main.done").unwrap());
+        assert_eq!(d.machine_mut().read(0x302, Unit::Byte), 7);
+        assert_eq!(d.last_step().unwrap().instruction, Some(Instruction::Iret));
+
+        assert_eq!(d.cont(100), Ok(Stop::Halted));
+        assert_eq!(d.machine_mut().read(0x300, Unit::Word), 0xBEF0);
+        assert_eq!(d.cont(100), Ok(Stop::Halted));
+    }
+
+    #[test]
+    fn test_breakpoints_and_conditions() {
+        let mut d = debugger();
+        let done = d.add_label_breakpoint("main.done").unwrap();
+        assert_eq!(d.add_label_breakpoint("main.nope"), None);
+        assert!(!d.add_breakpoint(done));
+        assert!(d.add_breakpoint(0x200));
+
+        // Stops where the handler writes 0x302, before the breakpoint after the int.
+        let stop = d.run_until(100, |_, step| {
+            step.writes.iter().any(|w| w.address == 0x302)
+        });
+        assert_eq!(stop, Ok(Stop::Condition));
+        let seven = d.label("handler.seven").unwrap();
+        assert_eq!(d.last_step().unwrap().effective.source, Some(seven));
+
+        assert_eq!(d.cont(100), Ok(Stop::Breakpoint(done)));
+        assert_eq!(d.cont(1), Ok(Stop::StepLimit));
+        assert!(d.remove_breakpoint(0x200));
+        assert_eq!(d.breakpoints().collect::<Vec<_>>(), vec![done]);
+
+        d.machine_mut().set_ip(0x1000);
+        d.machine_mut().load(0x1000, &[0xF0]);
+        assert_eq!(d.cont(100), Err(Fault::InvalidInstruction(0x1000)));
+    }
+}
@@ -0,0 +1,217 @@
+use super::*;
+
+use emulator::MemoryWrite;
+use {Instruction, Unit};
+
+use std::ops::RangeInclusive;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    // A write that changes the value.
+    Change,
+}
+
+/// Triggers on accesses that touch any byte of `range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+    // Only trigger if the value read or written is this one.
+    pub value: Option<u32>,
+}
+
+impl Watchpoint {
+    pub fn new(range: RangeInclusive<u16>, kind: WatchKind) -> Self {
+        Watchpoint {
+            range,
+            kind,
+            value: None,
+        }
+    }
+
+    pub fn with_value(self, value: u32) -> Self {
+        Watchpoint {
+            value: Some(value),
+            ..self
+        }
+    }
+
+    fn touches(&self, address: u16, unit: Unit) -> bool {
+        (0..unit.num_bytes() as u16).any(|i| self.range.contains(&address.wrapping_add(i)))
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WatchpointId(pub usize);
+
+/// An access that triggered a watchpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: WatchpointId,
+    // The instruction that made the access.
+    pub address: u16,
+    pub instruction: Option<Instruction>,
+    // The accessed address.
+    pub access: u16,
+    pub unit: Unit,
+    // For reads, both are the value read.
+    pub old: u32,
+    pub new: u32,
+}
+
+impl Debugger {
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> WatchpointId {
+        let id = WatchpointId(self.next_watchpoint);
+        self.next_watchpoint += 1;
+        self.watchpoints.push((id, watchpoint));
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> Option<Watchpoint> {
+        let i = self
+            .watchpoints
+            .iter()
+            .position(|&(other, _)| other == id)?;
+        Some(self.watchpoints.remove(i).1)
+    }
+
+    pub fn watchpoints(&self) -> &[(WatchpointId, Watchpoint)] {
+        &self.watchpoints
+    }
+
+    /// Every watchpoint `step` triggered, reads first, each in the order of the accesses.
+    pub fn watch_hits(&self, step: &Step) -> Vec<WatchHit> {
+        let hit = |id, access, unit, old, new| WatchHit {
+            watchpoint: id,
+            address: step.address,
+            instruction: step.instruction.clone(),
+            access,
+            unit,
+            old,
+            new,
+        };
+        let mut hits = Vec::new();
+        for read in &step.reads {
+            for &(id, ref watch) in &self.watchpoints {
+                if watch.kind == WatchKind::Read
+                    && watch.touches(read.address, read.unit)
+                    && watch.matches(read.value)
+                {
+                    hits.push(hit(id, read.address, read.unit, read.value, read.value));
+                }
+            }
+        }
+        for &MemoryWrite {
+            address,
+            unit,
+            old,
+            new,
+        } in &step.writes
+        {
+            for &(id, ref watch) in &self.watchpoints {
+                let triggers = match watch.kind {
+                    WatchKind::Read => false,
+                    WatchKind::Write => true,
+                    WatchKind::Change => old != new,
+                };
+                if triggers && watch.touches(address, unit) && watch.matches(new) {
+                    hits.push(hit(id, address, unit, old, new));
+                }
+            }
+        }
+        hits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    // Passes arguments by patching `add`'s slots, like the spec's example.
+    fn debugger() -> Debugger {
+        let assembly = assembler::assemble(
+            "main:
+    mov add.a, 0x300
+    mov add.b, 0x302
+    mov 0x300, 2
+.arg:
+    mov 0x302, 2
+    jmp add
+.back:
+    mov 0x302, 2
+    jmp .back
+add:
+    add @.a, @@.b
+    jmp main.back
+.a: db 2
+.b: db 2
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        Debugger::new(m, assembly.symbols)
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut d = debugger();
+        let (add, slot) = (d.label("add").unwrap(), d.label("add.b").unwrap());
+        // Only the low byte of the first argument.
+        let write = d.add_watchpoint(Watchpoint::new(0x301..=0x301, WatchKind::Write));
+        let read = d.add_watchpoint(Watchpoint::new(slot..=slot + 1, WatchKind::Read));
+
+        let hit = match d.cont(100) {
+            Ok(Stop::Watchpoint(hit)) => hit,
+            stop => panic!("{:?}", stop),
+        };
+        assert_eq!(hit.watchpoint, write);
+        assert_eq!(hit.instruction.unwrap().instr_str(), "mov");
+        assert_eq!(
+            (hit.access, hit.unit, hit.old, hit.new),
+            (0x300, Unit::Word, 0, 2)
+        );
+        assert_eq!(d.machine().ip(), d.label("main.arg").unwrap());
+        assert!(d.remove_watchpoint(write).is_some());
+
+        // The add reads its source pointer from the slot, then writes the sum.
+        let change = d.add_watchpoint(Watchpoint::new(0x300..=0x301, WatchKind::Change));
+        let step = d.step().unwrap();
+        assert!(d.watch_hits(&step).is_empty());
+        let step = d.step().unwrap();
+        assert!(d.watch_hits(&step).is_empty());
+        let step = d.step().unwrap();
+        let hits: Vec<_> = d
+            .watch_hits(&step)
+            .into_iter()
+            .map(|hit| (hit.watchpoint, hit.access, hit.old, hit.new))
+            .collect();
+        assert_eq!(step.address, add);
+        assert_eq!(
+            hits,
+            vec![(read, slot, 0x302, 0x302), (change, 0x300, 2, 4)]
+        );
+
+        // The loop keeps writing the same value, which is no change.
+        assert!(d.remove_watchpoint(read).is_some());
+        d.add_watchpoint(Watchpoint::new(0x302..=0x303, WatchKind::Change));
+        assert_eq!(d.cont(10), Ok(Stop::StepLimit));
+        let same = d.add_watchpoint(Watchpoint::new(0x302..=0x303, WatchKind::Write).with_value(2));
+        match d.cont(10) {
+            Ok(Stop::Watchpoint(ref hit)) => {
+                assert_eq!(hit.watchpoint, same);
+                assert_eq!(hit.address, d.label("main.back").unwrap());
+            }
+            ref stop => panic!("{:?}", stop),
+        }
+    }
+}
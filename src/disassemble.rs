@@ -1,57 +1,186 @@
 use super::*;
 
-#[derive(PartialEq)]
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::iter;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum DisassembleError {
-    NotEnoughData,
-    InvalidInstruction,
+    // How many more bytes the instruction needed. If the byte holding the operand kind is
+    // missing, this assumes the shortest encoding.
+    NotEnoughData(usize),
+    // The opcode byte.
+    InvalidInstruction(u8),
 }
 
-impl From<()> for DisassembleError {
-    fn from(_: ()) -> Self {
-        DisassembleError::NotEnoughData
+impl fmt::Display for DisassembleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DisassembleError::NotEnoughData(missing) => {
+                write!(
+                    f,
+                    "instruction is truncated, {} more byte(s) needed",
+                    missing
+                )
+            }
+            DisassembleError::InvalidInstruction(byte) => {
+                write!(f, "invalid instruction (opcode byte 0x{:02X})", byte)
+            }
+        }
     }
 }
 
+impl Error for DisassembleError {}
+
 pub type DisassembleResult = Result<Instruction, DisassembleError>;
 
+// What the decoders below fail with; `Instruction::disassemble` fills in the details.
+enum DecodeError {
+    Truncated,
+    Invalid,
+}
+
+impl From<()> for DecodeError {
+    fn from(_: ()) -> Self {
+        DecodeError::Truncated
+    }
+}
+
+type DecodeResult = Result<Instruction, DecodeError>;
+
+/// One item of `DisassembleIter`: an instruction (or why there is none) and where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Disassembled {
+    /// Offset of the first byte, relative to the start of the input.
+    pub offset: usize,
+    /// The encoded instruction. A single byte for invalid instructions, everything that was left
+    /// for truncated ones.
+    pub bytes: Vec<u8>,
+    pub result: DisassembleResult,
+}
+
+impl Disassembled {
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
 pub struct DisassembleIter<I> {
     iter: I,
+    offset: usize,
+    // Bytes an invalid instruction read past its opcode, to be decoded again.
+    pending: VecDeque<u8>,
 }
 
 impl<I: Iterator<Item = u8>> Iterator for DisassembleIter<I> {
-    type Item = Option<Instruction>;
+    type Item = Disassembled;
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some(byte) = self.iter.next() {
-            match Instruction::disassemble(byte, &mut self.iter) {
-                Ok(ins) => Some(Some(ins)),
-                Err(ref e) if *e == DisassembleError::InvalidInstruction => Some(None),
-                Err(_) => None,
+        let (pending, iter) = (&mut self.pending, &mut self.iter);
+        let mut input = iter::from_fn(|| pending.pop_front().or_else(|| iter.next()));
+        let b1 = input.next()?;
+
+        let mut bytes = vec![b1];
+        let result = {
+            let mut rest = input.inspect(|byte| bytes.push(*byte));
+            Instruction::disassemble(b1, &mut rest)
+        };
+        if let Err(DisassembleError::InvalidInstruction(_)) = result {
+            for byte in bytes.drain(1..).rev() {
+                self.pending.push_front(byte);
             }
-        } else {
-            None
         }
+
+        let offset = self.offset;
+        self.offset += bytes.len();
+        Some(Disassembled {
+            offset,
+            bytes,
+            result,
+        })
     }
 }
 
 pub fn disassemble<I: Iterator<Item = u8>>(input: I) -> DisassembleIter<I> {
-    DisassembleIter { iter: input }
+    DisassembleIter {
+        iter: input,
+        offset: 0,
+        pending: VecDeque::new(),
+    }
 }
 
 impl Instruction {
     pub fn disassemble<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
-        let id = b1 >> 4;
-        match id {
-            0b0000 => mov_add_sub_mul_div(b1, rest),
-            0b0001 => cmp(b1, rest),
-            0b0010 => jg_je_jl_jmp(b1, rest),
-            0b0011 => int_iret(b1, rest),
-            0b0100 => and_or_xor_not_shl_shr(b1, rest),
-            _ => Err(invins()),
-        }
+        let (mut b2, mut read) = (None, 0);
+        let res = {
+            let mut rest = rest.inspect(|byte| {
+                if read == 0 {
+                    b2 = Some(*byte);
+                }
+                read += 1;
+            });
+            decode(b1, &mut rest)
+        };
+        res.map_err(|err| match err {
+            DecodeError::Truncated => {
+                DisassembleError::NotEnoughData(encoded_len(b1, b2) - 1 - read)
+            }
+            DecodeError::Invalid => DisassembleError::InvalidInstruction(b1),
+        })
+    }
+}
+
+fn decode<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
+    let id = b1 >> 4;
+    match id {
+        0b0000 => decode_mov_add_sub_mul_div(b1, rest),
+        0b0001 => cmp(b1, rest),
+        0b0010 => jg_je_jl_jmp(b1, rest),
+        0b0011 => int_iret(b1, rest),
+        0b0100 => and_or_xor_not_shl_shr(b1, rest),
+        _ => Err(invins()),
     }
 }
 
+// Length of a (valid) instruction, judging by its first two bytes.
+fn encoded_len(b1: u8, b2: Option<u8>) -> usize {
+    let operands = |unit: Option<Unit>, is_value: bool| {
+        let source = match unit {
+            Some(unit) if is_value => unit.num_bytes() as usize,
+            None if is_value => 1,
+            _ => 2,
+        };
+        1 + 1 + 2 + source
+    };
+    match b1 >> 4 {
+        0b0000 => operands(Unit::from_id((b1 >> 2) & 0b11), b1 & 0b1 == 1),
+        0b0001 => operands(
+            Unit::from_id((b1 >> 2) & 0b11),
+            b2.is_none_or(|b2| b2 >> 7 == 1),
+        ),
+        0b0010 => 3,
+        0b0011 if b1 & 0b1 == 0 => 2,
+        0b0100 => operands(
+            b2.and_then(|b2| Unit::from_id(b2 >> 6)),
+            b2.is_none_or(|b2| b2 & 0b1 == 1),
+        ),
+        _ => 1,
+    }
+}
+
+/// Like `Instruction::disassemble`, but only for `mov`, `add`, `sub`, `mul` and `div`.
 pub fn mov_add_sub_mul_div<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
+    if b1 >> 4 != 0b0000 {
+        return Err(DisassembleError::InvalidInstruction(b1));
+    }
+    Instruction::disassemble(b1, rest)
+}
+
+fn decode_mov_add_sub_mul_div<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
     let unit = Unit::from_id((b1 >> 2) & 0b11).ok_or(invins())?;
     let b2 = rest.read_byte()?;
     let (dest_depth, source_depth) = ((b2 >> 2) & 0b11, b2 & 0b11);
@@ -70,10 +199,10 @@ pub fn mov_add_sub_mul_div<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> Disa
         )),
         0b1 => {
             let mut buf = [0u8; 4];
-            let _ = rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
+            rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
             let mut val = 0u32;
-            for i in 0..unit.num_bytes() as usize {
-                val = (val << 8) | buf[i] as u32;
+            for byte in &buf[0..unit.num_bytes() as usize] {
+                val = (val << 8) | *byte as u32;
             }
             Ok((
                 Address {
@@ -103,7 +232,7 @@ pub fn mov_add_sub_mul_div<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> Disa
     }
 }
 
-fn cmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
+fn cmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
     let unit = Unit::from_id((b1 >> 2) & 0b11).ok_or(invins())?;
     let b2 = rest.read_byte()?;
     let (dest_depth, source_depth) = (b1 & 0b11, (b2 >> 5) & 0b11);
@@ -122,10 +251,10 @@ fn cmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
         )),
         0b1 => {
             let mut buf = [0u8; 4];
-            let _ = rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
+            rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
             let mut val = 0u32;
-            for i in 0..unit.num_bytes() as usize {
-                val = (val << 8) | buf[i] as u32;
+            for byte in &buf[0..unit.num_bytes() as usize] {
+                val = (val << 8) | *byte as u32;
             }
             Ok((
                 Address {
@@ -145,7 +274,7 @@ fn cmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
     }))
 }
 
-fn jg_je_jl_jmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
+fn jg_je_jl_jmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
     let address = Address {
         location: rest.read_short()?,
         depth: b1 & 0b11,
@@ -160,7 +289,7 @@ fn jg_je_jl_jmp<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResu
     }
 }
 
-fn int_iret<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
+fn int_iret<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
     let ins2 = b1 & 0b1;
     match ins2 {
         0 => Ok(Instruction::Int(rest.read_byte()?)),
@@ -169,7 +298,7 @@ fn int_iret<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
     }
 }
 
-fn and_or_xor_not_shl_shr<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DisassembleResult {
+fn and_or_xor_not_shl_shr<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> DecodeResult {
     let b2 = rest.read_byte()?;
     let unit = Unit::from_id(b2 >> 6).ok_or(invins())?;
     let (dest_depth, source_depth) = ((b2 >> 4) & 0b11, (b2 >> 2) & 0b11);
@@ -188,10 +317,10 @@ fn and_or_xor_not_shl_shr<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> Disas
         )),
         0b1 => {
             let mut buf = [0u8; 4];
-            let _ = rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
+            rest.read_slice(&mut buf[0..unit.num_bytes() as usize])?;
             let mut val = 0u32;
-            for i in 0..unit.num_bytes() as usize {
-                val = (val << 8) | buf[i] as u32;
+            for byte in &buf[0..unit.num_bytes() as usize] {
+                val = (val << 8) | *byte as u32;
             }
             Ok((
                 Address {
@@ -222,8 +351,8 @@ fn and_or_xor_not_shl_shr<I: Iterator<Item = u8>>(b1: u8, rest: &mut I) -> Disas
     }
 }
 
-fn invins() -> DisassembleError {
-    DisassembleError::InvalidInstruction
+fn invins() -> DecodeError {
+    DecodeError::Invalid
 }
 
 trait IterExt {
@@ -242,9 +371,9 @@ impl<T: Iterator<Item = u8>> IterExt for T {
     }
 
     fn read_slice(&mut self, buf: &mut [u8]) -> Result<(), ()> {
-        for i in 0..buf.len() {
+        for slot in buf.iter_mut() {
             match self.next() {
-                Some(byte) => buf[i] = byte,
+                Some(byte) => *slot = byte,
                 None => return Err(()),
             }
         }
@@ -264,7 +393,7 @@ mod tests {
             let disas: Vec<_> = disassemble(binary.into_iter()).collect();
             let mut index = 0;
             $(
-                assert_eq!($instr, *disas[index].as_ref().unwrap());
+                assert_eq!($instr, *disas[index].result.as_ref().unwrap());
                 index += 1;
             )*
         }}
@@ -432,4 +561,56 @@ mod tests {
             })
         ]
     }
+
+    #[test]
+    fn test_offsets_and_errors() {
+        let (mut binary, _) = assemble(&[
+            Instruction::Iret,
+            Instruction::Jmp(Address {
+                location: 0x100,
+                depth: 0,
+            }),
+        ])
+        .unwrap();
+        binary.push(0xFF);
+        // A word mov from memory, missing the last byte of its source.
+        binary.extend_from_slice(&[0b0000_0100, 0, 0x01, 0x00, 0x02]);
+
+        let items: Vec<_> = disassemble(binary.into_iter()).collect();
+        let layout: Vec<_> = items.iter().map(|item| (item.offset, item.len())).collect();
+        assert_eq!(layout, vec![(0, 1), (1, 3), (4, 1), (5, 5)]);
+        assert_eq!(items[1].bytes, vec![0b0010_1100, 0x01, 0x00]);
+        assert_eq!(
+            items[2].result,
+            Err(DisassembleError::InvalidInstruction(0xFF))
+        );
+        assert_eq!(items[3].result, Err(DisassembleError::NotEnoughData(1)));
+
+        let mut rest = [0, 0x01, 0x00, 0x02].iter().cloned();
+        assert_eq!(
+            mov_add_sub_mul_div(0b0000_0100, &mut rest),
+            Err(DisassembleError::NotEnoughData(1))
+        );
+        let mut rest = iter::empty();
+        assert_eq!(
+            mov_add_sub_mul_div(0b0011_0001, &mut rest),
+            Err(DisassembleError::InvalidInstruction(0b0011_0001))
+        );
+    }
+
+    #[test]
+    fn test_invalid_instruction_resyncs() {
+        // A mov group opcode with an unknown sub-instruction; the following bytes are decoded
+        // again on their own.
+        let binary = vec![0b0000_0000, 0b1110_0000, 0x31, 0x31, 0x31, 0x00];
+        let items: Vec<_> = disassemble(binary.into_iter()).collect();
+        let layout: Vec<_> = items.iter().map(|item| (item.offset, item.len())).collect();
+        assert_eq!(layout, vec![(0, 1), (1, 1), (2, 1), (3, 1), (4, 1), (5, 1)]);
+        assert_eq!(
+            items[1].result,
+            Err(DisassembleError::InvalidInstruction(0b1110_0000))
+        );
+        assert_eq!(items[3].result, Ok(Instruction::Iret));
+        assert_eq!(items[5].result, Err(DisassembleError::NotEnoughData(5)));
+    }
 }
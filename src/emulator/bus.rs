@@ -0,0 +1,376 @@
+use super::*;
+
+use std::any::Any;
+use std::ops::RangeInclusive;
+
+/// A peripheral mapped into the address space.
+///
+/// Accesses are routed by their first address; `offset` is relative to the start of the
+/// device's range and the device handles the whole unit, even if it runs past its range.
+pub trait Device: Any {
+    fn read(&mut self, offset: u16, unit: Unit) -> u32;
+
+    fn write(&mut self, offset: u16, unit: Unit, value: u32);
+
+    /// Reads without side effects, for debuggers and dumps.
+    fn peek(&self, offset: u16, unit: Unit) -> u32;
+
+    /// Called after every executed instruction. Returns an interrupt to raise, if any.
+    fn tick(&mut self) -> Option<u8> {
+        None
+    }
+
+    /// The device's state for snapshots. Devices that don't opt in aren't saved.
+    fn save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Goes back to a state `save` returned. Returns whether that worked.
+    fn restore(&mut self, _state: &[u8]) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceId(pub(super) usize);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusError {
+    // The range overlaps the one of the device that is already attached.
+    Overlap(DeviceId),
+    // The range overlaps an unmapped one.
+    Unmapped,
+    EmptyRange,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BusError::Overlap(DeviceId(id)) => {
+                write!(f, "address range overlaps the one of device {}", id)
+            }
+            BusError::Unmapped => write!(f, "address range overlaps unmapped memory"),
+            BusError::EmptyRange => write!(f, "address range is empty"),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+struct Attached {
+    range: RangeInclusive<u16>,
+    device: Box<dyn Device>,
+}
+
+/// RAM with devices mapped over parts of it, and holes where nothing is mapped.
+pub struct Bus {
+    ram: Vec<u8>,
+    // Detached devices leave a `None` behind, so ids stay valid.
+    devices: Vec<Option<Attached>>,
+    unmapped: Vec<RangeInclusive<u16>>,
+}
+
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Bus {
+            ram: vec![0; MEMORY_SIZE],
+            devices: Vec::new(),
+            unmapped: Vec::new(),
+        }
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        &mut self.ram
+    }
+
+    pub fn attach<D: Device>(
+        &mut self,
+        range: RangeInclusive<u16>,
+        device: D,
+    ) -> Result<DeviceId, BusError> {
+        self.check_free(&range)?;
+        self.devices.push(Some(Attached {
+            range,
+            device: Box::new(device),
+        }));
+        Ok(DeviceId(self.devices.len() - 1))
+    }
+
+    /// Removes `range` from the address space. Reads there yield 0, writes are dropped and the
+    /// emulator faults on them.
+    pub fn unmap(&mut self, range: RangeInclusive<u16>) -> Result<(), BusError> {
+        self.check_free(&range)?;
+        self.unmapped.push(range);
+        Ok(())
+    }
+
+    pub fn is_mapped(&self, address: u16) -> bool {
+        !self.unmapped.iter().any(|range| range.contains(&address))
+    }
+
+    pub fn detach(&mut self, id: DeviceId) -> Option<Box<dyn Device>> {
+        self.devices
+            .get_mut(id.0)
+            .and_then(Option::take)
+            .map(|attached| attached.device)
+    }
+
+    pub fn device<D: Device>(&self, id: DeviceId) -> Option<&D> {
+        let attached = self.devices.get(id.0)?.as_ref()?;
+        (&*attached.device as &dyn Any).downcast_ref()
+    }
+
+    pub fn device_mut<D: Device>(&mut self, id: DeviceId) -> Option<&mut D> {
+        let attached = self.devices.get_mut(id.0)?.as_mut()?;
+        (&mut *attached.device as &mut dyn Any).downcast_mut()
+    }
+
+    /// The device mapped at `address`, if any.
+    pub fn device_at(&self, address: u16) -> Option<DeviceId> {
+        self.attached()
+            .find(|&(_, attached)| attached.range.contains(&address))
+            .map(|(id, _)| id)
+    }
+
+    /// Reads a big-endian value of `unit` width. Addresses wrap around at the end of memory.
+    pub fn read(&mut self, address: u16, unit: Unit) -> u32 {
+        if !self.is_mapped(address) {
+            return 0;
+        }
+        match self.route(address) {
+            Some((i, offset)) => {
+                self.devices[i].as_mut().unwrap().device.read(offset, unit) & mask(unit)
+            }
+            None => self.read_ram(address, unit),
+        }
+    }
+
+    pub fn peek(&self, address: u16, unit: Unit) -> u32 {
+        if !self.is_mapped(address) {
+            return 0;
+        }
+        match self.route(address) {
+            Some((i, offset)) => {
+                self.devices[i].as_ref().unwrap().device.peek(offset, unit) & mask(unit)
+            }
+            None => self.read_ram(address, unit),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, unit: Unit, value: u32) {
+        if !self.is_mapped(address) {
+            return;
+        }
+        match self.route(address) {
+            Some((i, offset)) => self.devices[i]
+                .as_mut()
+                .unwrap()
+                .device
+                .write(offset, unit, value),
+            None => {
+                let n = unit.num_bytes() as u16;
+                for i in 0..n {
+                    let shift = (n - 1 - i) * 8;
+                    self.ram[address.wrapping_add(i) as usize] = (value >> shift) as u8;
+                }
+            }
+        }
+    }
+
+    /// The state of every device that opts into snapshots, in attach order.
+    pub fn save_devices(&self) -> Vec<(DeviceId, Vec<u8>)> {
+        self.attached()
+            .filter_map(|(id, attached)| attached.device.save().map(|state| (id, state)))
+            .collect()
+    }
+
+    /// Returns whether the device is attached and took the state.
+    pub fn restore_device(&mut self, id: DeviceId, state: &[u8]) -> bool {
+        match self.devices.get_mut(id.0) {
+            Some(&mut Some(ref mut attached)) => attached.device.restore(state),
+            _ => false,
+        }
+    }
+
+    /// Ticks every device and collects the interrupts they raise, in attach order.
+    pub fn tick(&mut self) -> Vec<u8> {
+        self.devices
+            .iter_mut()
+            .flatten()
+            .filter_map(|attached| attached.device.tick())
+            .collect()
+    }
+
+    fn attached(&self) -> impl Iterator<Item = (DeviceId, &Attached)> {
+        self.devices
+            .iter()
+            .enumerate()
+            .filter_map(|(i, attached)| attached.as_ref().map(|a| (DeviceId(i), a)))
+    }
+
+    fn check_free(&self, range: &RangeInclusive<u16>) -> Result<(), BusError> {
+        let overlaps = |other: &RangeInclusive<u16>| {
+            range.start() <= other.end() && other.start() <= range.end()
+        };
+        if range.is_empty() {
+            return Err(BusError::EmptyRange);
+        }
+        if let Some((id, _)) = self.attached().find(|&(_, a)| overlaps(&a.range)) {
+            return Err(BusError::Overlap(id));
+        }
+        if self.unmapped.iter().any(overlaps) {
+            return Err(BusError::Unmapped);
+        }
+        Ok(())
+    }
+
+    fn route(&self, address: u16) -> Option<(usize, u16)> {
+        self.device_at(address).map(|DeviceId(i)| {
+            let start = *self.devices[i].as_ref().unwrap().range.start();
+            (i, address - start)
+        })
+    }
+
+    fn read_ram(&self, address: u16, unit: Unit) -> u32 {
+        (0..unit.num_bytes() as u16).fold(0, |val, i| {
+            (val << 8) | self.ram[address.wrapping_add(i) as usize] as u32
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    // Counts down and raises its interrupt at zero; the counter is a word at offset 0.
+    struct Timer {
+        counter: u16,
+        id: u8,
+    }
+
+    impl Device for Timer {
+        fn read(&mut self, offset: u16, unit: Unit) -> u32 {
+            self.peek(offset, unit)
+        }
+
+        fn write(&mut self, _: u16, _: Unit, value: u32) {
+            self.counter = value as u16;
+        }
+
+        fn peek(&self, _: u16, _: Unit) -> u32 {
+            self.counter as u32
+        }
+
+        fn tick(&mut self) -> Option<u8> {
+            if self.counter == 0 {
+                return None;
+            }
+            self.counter -= 1;
+            if self.counter == 0 {
+                Some(self.id)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Collects written bytes; reading pops from the input, 0 if empty.
+    #[derive(Default)]
+    struct Uart {
+        input: Vec<u8>,
+        output: Vec<u8>,
+    }
+
+    impl Device for Uart {
+        fn read(&mut self, _: u16, _: Unit) -> u32 {
+            if self.input.is_empty() {
+                0
+            } else {
+                self.input.remove(0) as u32
+            }
+        }
+
+        fn write(&mut self, _: u16, unit: Unit, value: u32) {
+            self.output.push((value & mask(unit)) as u8);
+        }
+
+        fn peek(&self, _: u16, _: Unit) -> u32 {
+            self.input.first().cloned().unwrap_or(0) as u32
+        }
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut bus = Bus::new();
+        let uart = bus.attach(0xFF00..=0xFF00, Uart::default()).unwrap();
+        assert_eq!(
+            bus.attach(0xFE00..=0xFF00, Uart::default()),
+            Err(BusError::Overlap(uart))
+        );
+
+        bus.device_mut::<Uart>(uart).unwrap().input = b"hi".to_vec();
+        assert_eq!(bus.peek(0xFF00, Unit::Byte), b'h' as u32);
+        assert_eq!(bus.read(0xFF00, Unit::Byte), b'h' as u32);
+        assert_eq!(bus.read(0xFF00, Unit::Byte), b'i' as u32);
+        bus.write(0xFF00, Unit::Word, 0x4142);
+        assert_eq!(bus.device::<Uart>(uart).unwrap().output, b"B");
+        assert!(bus.device::<Timer>(uart).is_none());
+
+        // Accesses starting just before the device only see RAM.
+        bus.write(0xFEFF, Unit::Word, 0x1234);
+        assert_eq!(bus.ram()[0xFF00], 0x34);
+        assert_eq!(bus.device::<Uart>(uart).unwrap().output, b"B");
+
+        assert!(bus.detach(uart).is_some());
+        assert_eq!(bus.read(0xFEFF, Unit::Word), 0x1234);
+    }
+
+    #[test]
+    fn test_device_interrupts() {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0xEE, handler
+    mov 0xFF10, 3
+.spin:
+    jmp .spin
+handler:
+    mov byte 0xFF00, 0x21
+    iret
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        let uart = m
+            .bus_mut()
+            .attach(0xFF00..=0xFF00, Uart::default())
+            .unwrap();
+        let timer = Timer {
+            counter: 0,
+            id: 0xEE,
+        };
+        m.bus_mut().attach(0xFF10..=0xFF11, timer).unwrap();
+
+        // The second mov sets the timer to 3 and ticks it to 2. The next two jumps count it down
+        // to 0, which enters the handler right away.
+        m.run(5).unwrap();
+        assert_eq!(m.interrupts().len(), 1);
+        assert_eq!(m.interrupts()[0].id, 0xEE);
+        m.run(2).unwrap();
+        assert!(m.interrupts().is_empty());
+        assert_eq!(m.bus().device::<Uart>(uart).unwrap().output, b"!");
+    }
+}
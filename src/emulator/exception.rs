@@ -0,0 +1,187 @@
+use super::*;
+
+/// Faults the program can handle itself, through a reserved interrupt vector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    DivisionByZero,
+    InvalidInstruction,
+    // An instruction that runs past the end of memory.
+    FetchPastEnd,
+    UnmappedAccess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionPolicy {
+    /// Stop with the `Fault`. The default.
+    Stop,
+    /// Enter the handler registered for `Exception::vector`, or stop if there is none.
+    ///
+    /// The handler returns to the instruction after the faulting one. For `InvalidInstruction`
+    /// and `FetchPastEnd` there is none, so it returns to the faulting instruction itself.
+    Trap,
+}
+
+impl Exception {
+    pub const ALL: [Exception; 4] = [
+        Exception::DivisionByZero,
+        Exception::InvalidInstruction,
+        Exception::FetchPastEnd,
+        Exception::UnmappedAccess,
+    ];
+
+    pub fn of(fault: &Fault) -> Option<Self> {
+        match *fault {
+            Fault::DivisionByZero(_) => Some(Exception::DivisionByZero),
+            Fault::InvalidInstruction(_) => Some(Exception::InvalidInstruction),
+            Fault::TruncatedInstruction(_) => Some(Exception::FetchPastEnd),
+            Fault::UnmappedAccess(..) => Some(Exception::UnmappedAccess),
+            _ => None,
+        }
+    }
+
+    /// The interrupt vector the exception dispatches through.
+    pub fn vector(&self) -> u8 {
+        match *self {
+            Exception::DivisionByZero => 0x00,
+            Exception::InvalidInstruction => 0x01,
+            Exception::FetchPastEnd => 0x02,
+            Exception::UnmappedAccess => 0x03,
+        }
+    }
+}
+
+impl Machine {
+    pub fn exception_policy(&self, exception: Exception) -> ExceptionPolicy {
+        if self.traps.contains(&exception) {
+            ExceptionPolicy::Trap
+        } else {
+            ExceptionPolicy::Stop
+        }
+    }
+
+    pub fn set_exception_policy(&mut self, exception: Exception, policy: ExceptionPolicy) {
+        self.traps.retain(|e| *e != exception);
+        if policy == ExceptionPolicy::Trap {
+            self.traps.push(exception);
+        }
+    }
+
+    /// Enters the handler of the exception `fault` stands for, returning to `next`. Hands the
+    /// fault back if it isn't an exception, isn't trapped or has no handler.
+    pub(super) fn trap(&mut self, fault: Fault, next: u16) -> Result<Exception, Fault> {
+        let exception = match Exception::of(&fault) {
+            Some(exception) if self.exception_policy(exception) == ExceptionPolicy::Trap => {
+                exception
+            }
+            _ => return Err(fault),
+        };
+        let ip = self.ip;
+        self.ip = next;
+        if self.interrupt(fault.address(), exception.vector()).is_err() {
+            self.ip = ip;
+            return Err(fault);
+        }
+        Ok(exception)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    const HANDLER: u16 = 0x300;
+
+    fn machine(code: &str) -> Machine {
+        let assembly = assembler::assemble(code, 0x200).unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        // The handler just returns.
+        m.load(HANDLER, &[0b0011_0001]);
+        m.set_ip(0x200);
+        m
+    }
+
+    #[test]
+    fn test_stop_by_default() {
+        let mut m = machine("div 0x100, 0");
+        let vector = m.vector_table().entry(Exception::DivisionByZero.vector());
+        m.write(vector, Unit::Word, HANDLER as u32);
+        assert_eq!(m.step(), Err(Fault::DivisionByZero(0x200)));
+        assert_eq!(m.ip(), 0x200);
+    }
+
+    #[test]
+    fn test_trap() {
+        // 0x200: div, 0x206: mov through a pointer into the hole, 0x20C: an invalid opcode
+        let mut m = machine(
+            "div 0x100, 0
+mov @0x110, 1
+db 1 0xFF",
+        );
+        m.bus_mut().unmap(0xE000..=0xEFFF).unwrap();
+        m.write(0x110, Unit::Word, 0xE000);
+        for exception in &[
+            Exception::DivisionByZero,
+            Exception::InvalidInstruction,
+            Exception::UnmappedAccess,
+        ] {
+            m.set_exception_policy(*exception, ExceptionPolicy::Trap);
+            let vector = m.vector_table().entry(exception.vector());
+            m.write(vector, Unit::Word, HANDLER as u32);
+        }
+
+        let step = m.step().unwrap();
+        assert_eq!(step.exception, Some(Exception::DivisionByZero));
+        assert_eq!(m.ip(), HANDLER);
+        assert_eq!(m.interrupts()[0].return_address, 0x206);
+        m.step().unwrap();
+
+        assert_eq!(m.step().unwrap().exception, Some(Exception::UnmappedAccess));
+        assert_eq!(m.interrupts()[0].id, 0x03);
+        m.step().unwrap();
+        assert_eq!(m.ip(), 0x20C);
+
+        let step = m.step().unwrap();
+        assert_eq!(step.exception, Some(Exception::InvalidInstruction));
+        assert_eq!(step.instruction, None);
+        m.step().unwrap();
+        assert_eq!(m.ip(), 0x20C);
+
+        // Without a handler, the fault stops the machine after all.
+        m.set_exception_policy(Exception::FetchPastEnd, ExceptionPolicy::Trap);
+        m.load(0xFFFF, &[0b0011_0000]);
+        assert_eq!(m.step(), Err(Fault::TruncatedInstruction(0xFFFF)));
+        assert_eq!(m.ip(), 0xFFFF);
+
+        m.set_exception_policy(Exception::InvalidInstruction, ExceptionPolicy::Stop);
+        m.set_ip(0x20C);
+        assert_eq!(m.step(), Err(Fault::InvalidInstruction(0x20C)));
+    }
+
+    #[test]
+    fn test_default_vectors() {
+        // The vectors are words of their own, away from a program loaded at 0.
+        let vectors = VectorTable::default();
+        let mut entries: Vec<_> = Exception::ALL
+            .iter()
+            .map(|exception| vectors.entry(exception.vector()))
+            .collect();
+        entries.sort();
+        assert!(entries.windows(2).all(|pair| pair[1] - pair[0] >= 2));
+
+        let assembly = assembler::assemble("div 0x100, 0", 0).unwrap();
+        let mut m = Machine::new();
+        m.load(HANDLER, &[0b0011_0001]);
+        m.load(0, &assembly.binary);
+        assert!(entries[0] as usize >= assembly.binary.len());
+        m.set_exception_policy(Exception::DivisionByZero, ExceptionPolicy::Trap);
+        // Unregistered, the fault stops the machine instead of jumping through the program.
+        assert_eq!(m.step(), Err(Fault::DivisionByZero(0)));
+
+        m.write(entries[0], Unit::Word, HANDLER as u32);
+        assert_eq!(m.step().unwrap().exception, Some(Exception::DivisionByZero));
+        assert_eq!(m.ip(), HANDLER);
+    }
+}
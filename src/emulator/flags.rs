@@ -0,0 +1,209 @@
+use super::*;
+
+/// The flags register.
+///
+/// `cmp unit dst, src` sets all of them from `dst - src`, computed at the width of `unit`:
+///
+/// * `equal`: `dst == src`.
+/// * `greater`, `less`: `dst` is greater/less than `src`, both read as two's complement numbers.
+///   This is what `jg` and `jl` test, so `cmp byte x, -1` does what it looks like.
+/// * `carry`: the subtraction borrows, i.e. `dst < src` read as unsigned numbers.
+/// * `overflow`: the signed subtraction doesn't fit into `unit`.
+///
+/// `add`, `sub` and `mul` set `carry` and `overflow` the same way for their result and leave the
+/// other flags alone. No other instruction changes the flags.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub equal: bool,
+    pub greater: bool,
+    pub less: bool,
+    pub carry: bool,
+    pub overflow: bool,
+}
+
+impl Flags {
+    pub fn compare(dst: u32, src: u32, unit: Unit) -> Self {
+        let (sdst, ssrc) = (sign_extend(dst, unit), sign_extend(src, unit));
+        let mut flags = Flags {
+            equal: dst == src,
+            greater: sdst > ssrc,
+            less: sdst < ssrc,
+            ..Flags::default()
+        };
+        flags.set_arithmetic(dst as i128 - src as i128, sdst as i128 - ssrc as i128, unit);
+        flags
+    }
+
+    /// Sets `carry` and `overflow` from the exact result of an operation on the unsigned and
+    /// on the signed reading of its operands.
+    pub fn set_arithmetic(&mut self, unsigned: i128, signed: i128, unit: Unit) {
+        let bits = unit.num_bytes() as u32 * 8;
+        self.carry = unsigned < 0 || unsigned > mask(unit) as i128;
+        self.overflow = signed < -(1i128 << (bits - 1)) || signed >= 1i128 << (bits - 1);
+    }
+
+    /// Packs the flags into the low bits, `equal` first, in the order they are declared.
+    pub fn bits(&self) -> u8 {
+        [
+            self.equal,
+            self.greater,
+            self.less,
+            self.carry,
+            self.overflow,
+        ]
+        .iter()
+        .enumerate()
+        .fold(0, |bits, (i, &set)| bits | (set as u8) << i)
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Flags {
+            equal: bits & 1 != 0,
+            greater: bits & 2 != 0,
+            less: bits & 4 != 0,
+            carry: bits & 8 != 0,
+            overflow: bits & 16 != 0,
+        }
+    }
+}
+
+impl fmt::Display for Flags {
+    /// Shows each flag as its initial if set, `-` if not, e.g. `-G-C-`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            (self.equal, 'E'),
+            (self.greater, 'G'),
+            (self.less, 'L'),
+            (self.carry, 'C'),
+            (self.overflow, 'O'),
+        ];
+        for &(set, name) in &flags {
+            write!(f, "{}", if set { name } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the low `unit` bits of `value` as a two's complement number.
+pub fn sign_extend(value: u32, unit: Unit) -> i64 {
+    let shift = 64 - unit.num_bytes() as u32 * 8;
+    ((value as i64) << shift) >> shift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UNITS: [Unit; 3] = [Unit::Byte, Unit::Word, Unit::Dword];
+
+    type Opcode = fn(Usd) -> Instruction;
+
+    // The smallest negative number of the unit, i.e. only the sign bit set.
+    fn min(unit: Unit) -> u32 {
+        1 << (unit.num_bytes() as u32 * 8 - 1)
+    }
+
+    fn run(ins: Opcode, unit: Unit, dst: u32, src: u32) -> (u32, Flags) {
+        let (binary, _) = assemble(&[ins(Usd {
+            unit,
+            source: Source::Value(src),
+            destination: Address {
+                location: 0x100,
+                depth: 0,
+            },
+        })])
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0, &binary);
+        m.write(0x100, unit, dst);
+        m.step().unwrap();
+        (m.read(0x100, unit), m.flags())
+    }
+
+    fn flags(s: &str) -> Flags {
+        Flags {
+            equal: s.contains('E'),
+            greater: s.contains('G'),
+            less: s.contains('L'),
+            carry: s.contains('C'),
+            overflow: s.contains('O'),
+        }
+    }
+
+    #[test]
+    fn test_cmp() {
+        for &unit in &UNITS {
+            let (max, min) = (mask(unit), min(unit));
+            let cases = [
+                (5, 5, "E"),
+                (6, 5, "G"),
+                (5, 6, "LC"),
+                // -1 < 0, but 0xFF.. > 0 unsigned
+                (max, 0, "L"),
+                (0, max, "GC"),
+                (max, max - 1, "G"),
+                // MIN < MAX, and MIN - MAX overflows
+                (min, min - 1, "LO"),
+                (min - 1, min, "GCO"),
+                (min, 1, "LO"),
+            ];
+            for &(dst, src, expected) in &cases {
+                let (val, res) = run(Instruction::Cmp, unit, dst, src);
+                assert_eq!(val, dst);
+                assert_eq!(res, flags(expected), "{:?} {:X} {:X}", unit, dst, src);
+            }
+        }
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        for &unit in &UNITS {
+            let (max, min) = (mask(unit), min(unit));
+            let cases: [(Opcode, u32, u32, u32, &str); 10] = [
+                (Instruction::Add, 1, 2, 3, ""),
+                (Instruction::Add, max, 1, 0, "C"),
+                (Instruction::Add, min - 1, 1, min, "O"),
+                (Instruction::Add, min, min, 0, "CO"),
+                (Instruction::Sub, 3, 2, 1, ""),
+                (Instruction::Sub, 0, 1, max, "C"),
+                (Instruction::Sub, min, 1, min - 1, "O"),
+                (Instruction::Mul, 3, 4, 12, ""),
+                // -1 * -1 = 1 fits signed, but not unsigned
+                (Instruction::Mul, max, max, 1, "C"),
+                (Instruction::Mul, min, 2, 0, "CO"),
+            ];
+            for &(ins, dst, src, result, expected) in &cases {
+                let (val, res) = run(ins, unit, dst, src);
+                assert_eq!(val, result, "{:?} {:X} {:X}", unit, dst, src);
+                assert_eq!(res, flags(expected), "{:?} {:X} {:X}", unit, dst, src);
+            }
+        }
+    }
+
+    #[test]
+    fn test_other_instructions_keep_flags() {
+        let usd = |source| Usd {
+            unit: Unit::Byte,
+            source,
+            destination: Address {
+                location: 0x100,
+                depth: 0,
+            },
+        };
+        let (binary, _) = assemble(&[
+            Instruction::Cmp(usd(Source::Value(1))),
+            Instruction::Div(usd(Source::Value(3))),
+            Instruction::Shl(usd(Source::Value(9))),
+            Instruction::Mov(usd(Source::Value(0xFF))),
+        ])
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0, &binary);
+        m.run(4).unwrap();
+        assert_eq!(m.flags(), flags("LC"));
+
+        assert_eq!(flags("EC").to_string(), "E--C-");
+        assert_eq!(sign_extend(0xFFFE, Unit::Word), -2);
+        assert_eq!(sign_extend(0xFFFE, Unit::Dword), 0xFFFE);
+    }
+}
@@ -0,0 +1,216 @@
+use super::*;
+
+use std::io::{self, Read, Write};
+
+pub const PRINT_STRING: u8 = 0x10;
+pub const PRINT_NUMBER: u8 = 0x11;
+pub const HALT: u8 = 0x12;
+pub const READ_CHAR: u8 = 0x13;
+
+/// Where the built-in services expect their argument (and `READ_CHAR` leaves its result).
+pub const ARGUMENT: u16 = 0x101;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostAction {
+    Continue,
+    Halt,
+}
+
+/// Interrupts implemented by the host instead of a handler in emulated memory.
+pub trait HostInterrupt {
+    /// Handles `int id`, or returns `None` to leave it to the vector table.
+    fn interrupt(&mut self, id: u8, machine: &mut Machine) -> Option<HostAction>;
+}
+
+pub type HostHandler = Box<dyn FnMut(&mut Machine) -> HostAction>;
+
+/// The built-in services:
+///
+/// * `PRINT_STRING` writes the NUL-terminated string whose address is the word at `ARGUMENT`.
+/// * `PRINT_NUMBER` writes the word at `ARGUMENT` in decimal.
+/// * `HALT` stops the machine.
+/// * `READ_CHAR` reads a byte into the word at `ARGUMENT`, 0xFFFF at the end of input.
+pub struct Services<R, W> {
+    pub input: R,
+    pub output: W,
+}
+
+impl Services<io::Stdin, io::Stdout> {
+    pub fn stdio() -> Self {
+        Services {
+            input: io::stdin(),
+            output: io::stdout(),
+        }
+    }
+}
+
+impl<R: Read, W: Write> Services<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Services { input, output }
+    }
+}
+
+impl<R: Read, W: Write> HostInterrupt for Services<R, W> {
+    fn interrupt(&mut self, id: u8, machine: &mut Machine) -> Option<HostAction> {
+        // Like `print!`, the services have no way to report a broken output to the program.
+        match id {
+            PRINT_STRING => {
+                let mut address = machine.read_word(ARGUMENT);
+                let mut string = Vec::new();
+                while string.len() < MEMORY_SIZE {
+                    match machine.read(address, Unit::Byte) as u8 {
+                        0 => break,
+                        byte => string.push(byte),
+                    }
+                    address = address.wrapping_add(1);
+                }
+                let _ = self
+                    .output
+                    .write_all(&string)
+                    .and_then(|_| self.output.flush());
+            }
+            PRINT_NUMBER => {
+                let number = machine.read_word(ARGUMENT);
+                let _ = write!(self.output, "{}", number).and_then(|_| self.output.flush());
+            }
+            HALT => return Some(HostAction::Halt),
+            READ_CHAR => {
+                let mut buf = [0];
+                let value = match self.input.read(&mut buf) {
+                    Ok(1) => buf[0] as u32,
+                    _ => 0xFFFF,
+                };
+                machine.write(ARGUMENT, Unit::Word, value);
+            }
+            _ => return None,
+        }
+        Some(HostAction::Continue)
+    }
+}
+
+impl Machine {
+    /// Installs `host`, which is asked about every interrupt without a handler registered
+    /// through `on_interrupt`.
+    pub fn set_host<H: HostInterrupt + 'static>(&mut self, host: H) {
+        self.host = Some(Box::new(host));
+    }
+
+    /// Handles `int id` with `handler` instead of the host or the vector table.
+    pub fn on_interrupt<F: FnMut(&mut Machine) -> HostAction + 'static>(
+        &mut self,
+        id: u8,
+        handler: F,
+    ) {
+        self.host_handlers.insert(id, Box::new(handler));
+    }
+
+    pub fn remove_interrupt_handler(&mut self, id: u8) -> Option<HostHandler> {
+        self.host_handlers.remove(&id)
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Lets `run` continue after a `HALT`.
+    pub fn resume(&mut self) {
+        self.halted = false;
+    }
+
+    /// Gives the host a chance to handle `int id`. Returns whether it did.
+    pub(super) fn host_interrupt(&mut self, id: u8) -> bool {
+        // The handlers are taken out while they run, so they can have the whole machine.
+        let action = if let Some(mut handler) = self.host_handlers.remove(&id) {
+            let action = handler(self);
+            self.host_handlers.entry(id).or_insert(handler);
+            Some(action)
+        } else if let Some(mut host) = self.host.take() {
+            let action = host.interrupt(id, self);
+            self.host.get_or_insert(host);
+            action
+        } else {
+            None
+        };
+
+        match action {
+            Some(HostAction::Halt) => self.halted = true,
+            Some(HostAction::Continue) => {}
+            None => return false,
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn machine(code: &str) -> Machine {
+        let assembly = assembler::assemble(code, 0x200).unwrap();
+        let mut machine = Machine::new();
+        machine.load(0x200, &assembly.binary);
+        machine
+    }
+
+    #[test]
+    fn test_services() {
+        let mut m = machine("");
+        let mut services = Services::new(&b"a"[..], Vec::new());
+        m.write(ARGUMENT, Unit::Word, 0x300);
+        m.load(0x300, b"Hello\0world");
+        assert_eq!(
+            services.interrupt(PRINT_STRING, &mut m),
+            Some(HostAction::Continue)
+        );
+        m.write(ARGUMENT, Unit::Word, 1234);
+        services.interrupt(PRINT_NUMBER, &mut m);
+        assert_eq!(services.output, b"Hello1234");
+
+        services.interrupt(READ_CHAR, &mut m);
+        assert_eq!(m.read_word(ARGUMENT), b'a' as u16);
+        services.interrupt(READ_CHAR, &mut m);
+        assert_eq!(m.read_word(ARGUMENT), 0xFFFF);
+
+        assert_eq!(services.interrupt(HALT, &mut m), Some(HostAction::Halt));
+        assert_eq!(services.interrupt(0xEE, &mut m), None);
+    }
+
+    #[test]
+    fn test_handlers_and_halt() {
+        let mut m = machine(
+            "main:
+    int 0x10
+    int 0xEE
+    int 0x12
+    int 0x10
+",
+        );
+        m.set_host(Services::new(io::empty(), io::sink()));
+        let vector = m.vector_table().entry(0xEE);
+        m.write(vector, Unit::Word, 0x280);
+        m.load(0x280, &[0b0011_0001]);
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let log = calls.clone();
+        m.on_interrupt(0x10, move |m| {
+            log.borrow_mut().push(m.ip());
+            HostAction::Continue
+        });
+        m.set_ip(0x200);
+
+        assert_eq!(m.run(100), Ok(4));
+        assert!(m.halted());
+        assert_eq!(*calls.borrow(), vec![0x202]);
+        // `int 0xEE` went through the vector table.
+        assert!(m.interrupts().is_empty());
+
+        m.resume();
+        m.remove_interrupt_handler(0x10);
+        m.run(1).unwrap();
+        assert_eq!(m.ip(), 0x208);
+        assert_eq!(*calls.borrow(), vec![0x202]);
+    }
+}
@@ -0,0 +1,87 @@
+use super::*;
+
+/// How deeply interrupts may nest before `int` faults.
+pub const MAX_NESTING: usize = 64;
+
+/// Where `int id` looks up its handler: the word at `base + id * stride`.
+///
+/// By default every vector is a word of its own and the table starts at 0xFF12, wrapping around
+/// the end of memory. That way `mov 0xEE, handle_ee` registers the handler of `int 0xEE` like in
+/// `EMPU_spec.asm`, and the exception vectors take 0xFF12-0xFF19, out of the way of programs
+/// loaded low in memory. A handler address of 0 means the vector is unregistered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorTable {
+    pub base: u16,
+    pub stride: u16,
+}
+
+impl Default for VectorTable {
+    fn default() -> Self {
+        VectorTable {
+            base: 0xFF12,
+            stride: 2,
+        }
+    }
+}
+
+impl VectorTable {
+    /// The address of the word holding the handler of `id`.
+    pub fn entry(&self, id: u8) -> u16 {
+        self.base
+            .wrapping_add((id as u16).wrapping_mul(self.stride))
+    }
+}
+
+/// The state `int` saves and `iret` restores.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub id: u8,
+    pub return_address: u16,
+    pub flags: Flags,
+}
+
+impl Machine {
+    pub fn vector_table(&self) -> VectorTable {
+        self.vectors
+    }
+
+    pub fn set_vector_table(&mut self, vectors: VectorTable) {
+        self.vectors = vectors;
+    }
+
+    /// The interrupts currently being handled, innermost last.
+    pub fn interrupts(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Device interrupts raised but not entered yet, oldest first.
+    pub fn pending_interrupts(&self) -> &VecDeque<u8> {
+        &self.pending
+    }
+
+    /// Enters the handler of `id`, returning to the instruction pointer once it `iret`s.
+    /// `address` is the instruction that raised the interrupt.
+    pub fn interrupt(&mut self, address: u16, id: u8) -> Result<(), Fault> {
+        let handler = self.read_word(self.vectors.entry(id));
+        if handler == 0 {
+            return Err(Fault::UnregisteredInterrupt(address, id));
+        }
+        if self.frames.len() >= MAX_NESTING {
+            return Err(Fault::InterruptOverflow(address));
+        }
+        self.frames.push(Frame {
+            id,
+            return_address: self.ip,
+            flags: self.flags,
+        });
+        self.ip = handler;
+        Ok(())
+    }
+
+    pub(super) fn iret(&mut self, address: u16) -> Result<(), Fault> {
+        let frame = self.frames.pop().ok_or(Fault::IretWithoutInt(address))?;
+        self.ip = frame.return_address;
+        self.flags = frame.flags;
+        Ok(())
+    }
+}
@@ -0,0 +1,780 @@
+use super::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+pub mod bus;
+mod exception;
+mod flags;
+pub mod host;
+mod interrupt;
+mod snapshot;
+mod timing;
+
+pub use self::bus::{Bus, BusError, Device, DeviceId};
+pub use self::exception::{Exception, ExceptionPolicy};
+pub use self::flags::{sign_extend, Flags};
+pub use self::host::{HostAction, HostHandler, HostInterrupt, Services};
+pub use self::interrupt::{Frame, VectorTable, MAX_NESTING};
+pub use self::snapshot::{Snapshot, SnapshotError, SNAPSHOT_VERSION};
+pub use self::timing::CostTable;
+
+pub const MEMORY_SIZE: usize = 0x10000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fault {
+    InvalidInstruction(u16),
+    TruncatedInstruction(u16),
+    DivisionByZero(u16),
+    IretWithoutInt(u16),
+    // The interrupt id has no handler in the vector table.
+    UnregisteredInterrupt(u16, u8),
+    // More than `MAX_NESTING` nested interrupts.
+    InterruptOverflow(u16),
+    // The instruction accessed an unmapped address (the second one), directly or through a
+    // pointer.
+    UnmappedAccess(u16, u16),
+}
+
+impl Fault {
+    pub fn address(&self) -> u16 {
+        match *self {
+            Fault::InvalidInstruction(adr)
+            | Fault::TruncatedInstruction(adr)
+            | Fault::DivisionByZero(adr)
+            | Fault::IretWithoutInt(adr)
+            | Fault::UnregisteredInterrupt(adr, _)
+            | Fault::InterruptOverflow(adr)
+            | Fault::UnmappedAccess(adr, _) => adr,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::InvalidInstruction(adr) => write!(f, "invalid instruction at 0x{:04X}", adr),
+            Fault::TruncatedInstruction(adr) => {
+                write!(
+                    f,
+                    "instruction at 0x{:04X} runs past the end of memory",
+                    adr
+                )
+            }
+            Fault::DivisionByZero(adr) => write!(f, "division by zero at 0x{:04X}", adr),
+            Fault::IretWithoutInt(adr) => {
+                write!(f, "iret without an active interrupt at 0x{:04X}", adr)
+            }
+            Fault::UnregisteredInterrupt(adr, id) => {
+                write!(f, "no handler for interrupt 0x{:02X} at 0x{:04X}", id, adr)
+            }
+            Fault::InterruptOverflow(adr) => write!(
+                f,
+                "more than {} nested interrupts at 0x{:04X}",
+                MAX_NESTING, adr
+            ),
+            Fault::UnmappedAccess(adr, unmapped) => write!(
+                f,
+                "access to unmapped address 0x{:04X} at 0x{:04X}",
+                unmapped, adr
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
+
+/// An access to unmapped memory, at the contained address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unmapped(pub u16);
+
+// Why executing an instruction failed, before unmapped accesses are turned into faults.
+enum ExecError {
+    Fault(Fault),
+    Unmapped(Unmapped),
+}
+
+impl From<Fault> for ExecError {
+    fn from(fault: Fault) -> Self {
+        ExecError::Fault(fault)
+    }
+}
+
+impl From<Unmapped> for ExecError {
+    fn from(unmapped: Unmapped) -> Self {
+        ExecError::Unmapped(unmapped)
+    }
+}
+
+/// Where an instruction's operands point once every `@` is followed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EffectiveAddresses {
+    // The destination, or the target of a jump.
+    pub destination: Option<u16>,
+    // Where the source value is read from. `None` for immediates and plain addresses.
+    pub source: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRead {
+    pub address: u16,
+    pub unit: Unit,
+    pub value: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u16,
+    pub unit: Unit,
+    pub old: u32,
+    pub new: u32,
+}
+
+impl MemoryWrite {
+    /// The written bytes, in memory order.
+    pub fn bytes(&self) -> Vec<u8> {
+        let n = self.unit.num_bytes() as u32;
+        (0..n)
+            .map(|i| (self.new >> ((n - 1 - i) * 8)) as u8)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub address: u16,
+    // 0 if the instruction couldn't be decoded.
+    pub length: u8,
+    pub instruction: Option<Instruction>,
+    pub effective: EffectiveAddresses,
+    // Everything the instruction read and wrote, including through host interrupts, in order.
+    // Fetching the instruction itself doesn't count.
+    pub reads: Vec<MemoryRead>,
+    pub writes: Vec<MemoryWrite>,
+    // The exception the instruction trapped into, if any.
+    pub exception: Option<Exception>,
+    // What the instruction cost, see `CostTable`.
+    pub cycles: u32,
+}
+
+/// Everything about a machine besides memory and its devices.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub ip: u16,
+    pub flags: Flags,
+    pub frames: Vec<Frame>,
+    pub halted: bool,
+    // Device interrupts not entered yet, oldest first.
+    pub pending: Vec<u8>,
+    pub steps: u64,
+    pub cycles: u64,
+}
+
+pub struct Machine {
+    bus: Bus,
+    ip: u16,
+    flags: Flags,
+    vectors: VectorTable,
+    frames: Vec<Frame>,
+    host: Option<Box<dyn HostInterrupt>>,
+    host_handlers: HashMap<u8, HostHandler>,
+    halted: bool,
+    // Raised by devices, entered one per step.
+    pending: VecDeque<u8>,
+    // Exceptions that trap into the program instead of stopping it.
+    traps: Vec<Exception>,
+    // Collect the accesses of the current step.
+    reads: Option<Vec<MemoryRead>>,
+    writes: Option<Vec<MemoryWrite>>,
+    steps: u64,
+    costs: CostTable,
+    cycles: u64,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Machine {
+    pub fn new() -> Self {
+        Self {
+            bus: Bus::new(),
+            ip: 0,
+            flags: Flags::default(),
+            vectors: VectorTable::default(),
+            frames: Vec::new(),
+            host: None,
+            host_handlers: HashMap::new(),
+            halted: false,
+            pending: VecDeque::new(),
+            traps: Vec::new(),
+            reads: None,
+            writes: None,
+            steps: 0,
+            costs: CostTable::default(),
+            cycles: 0,
+        }
+    }
+
+    /// Copies `binary` into RAM at `origin` and points the instruction pointer at it.
+    pub fn load(&mut self, origin: u16, binary: &[u8]) {
+        let ram = self.bus.ram_mut();
+        for (i, byte) in binary.iter().enumerate() {
+            ram[origin.wrapping_add(i as u16) as usize] = *byte;
+        }
+        self.ip = origin;
+    }
+
+    /// RAM, without the devices mapped over it.
+    pub fn memory(&self) -> &[u8] {
+        self.bus.ram()
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        self.bus.ram_mut()
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn ip(&self) -> u16 {
+        self.ip
+    }
+
+    pub fn set_ip(&mut self, ip: u16) {
+        self.ip = ip;
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.flags
+    }
+
+    pub fn set_flags(&mut self, flags: Flags) {
+        self.flags = flags;
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        CpuState {
+            ip: self.ip,
+            flags: self.flags,
+            frames: self.frames.clone(),
+            halted: self.halted,
+            pending: self.pending.iter().cloned().collect(),
+            steps: self.steps,
+            cycles: self.cycles,
+        }
+    }
+
+    pub fn set_cpu_state(&mut self, state: CpuState) {
+        self.ip = state.ip;
+        self.flags = state.flags;
+        self.frames = state.frames;
+        self.halted = state.halted;
+        self.pending = state.pending.into_iter().collect();
+        self.steps = state.steps;
+        self.cycles = state.cycles;
+    }
+
+    /// Reads a big-endian value of `unit` width through the bus. Addresses wrap around at the
+    /// end of memory.
+    pub fn read(&mut self, address: u16, unit: Unit) -> u32 {
+        let value = self.bus.read(address, unit);
+        if self.bus.is_mapped(address) {
+            if let Some(ref mut reads) = self.reads {
+                reads.push(MemoryRead {
+                    address,
+                    unit,
+                    value,
+                });
+            }
+        }
+        value
+    }
+
+    /// Like `read`, but without side effects on devices.
+    pub fn peek(&self, address: u16, unit: Unit) -> u32 {
+        self.bus.peek(address, unit)
+    }
+
+    pub fn write(&mut self, address: u16, unit: Unit, value: u32) {
+        if self.bus.is_mapped(address) {
+            if let Some(ref mut writes) = self.writes {
+                writes.push(MemoryWrite {
+                    address,
+                    unit,
+                    old: self.bus.peek(address, unit),
+                    new: value & mask(unit),
+                });
+            }
+        }
+        self.bus.write(address, unit, value)
+    }
+
+    pub fn read_word(&mut self, address: u16) -> u16 {
+        self.read(address, Unit::Word) as u16
+    }
+
+    /// Follows `adr.depth` pointers starting at `adr.location`. Fails if a pointer lies in
+    /// unmapped memory.
+    pub fn resolve(&mut self, adr: &Address) -> Result<u16, Unmapped> {
+        let mut address = adr.location;
+        for _ in 0..adr.depth {
+            address = self.checked_read(address, Unit::Word)? as u16;
+        }
+        Ok(address)
+    }
+
+    /// The value a source operand evaluates to: a pointer of depth `n` reads memory `n` times,
+    /// the last time with the width of `unit`. Fails like `resolve`.
+    pub fn source_value(&mut self, source: &Source, unit: Unit) -> Result<u32, Unmapped> {
+        match *source {
+            Source::Value(val) => Ok(val & mask(unit)),
+            Source::Pointer(ref adr) if adr.depth == 0 => Ok(adr.location as u32 & mask(unit)),
+            Source::Pointer(ref adr) => {
+                let last = Address {
+                    location: adr.location,
+                    depth: adr.depth - 1,
+                };
+                let address = self.resolve(&last)?;
+                self.checked_read(address, unit)
+            }
+        }
+    }
+
+    /// Where the operands of `instruction` currently point. Like `peek`, this has no side
+    /// effects.
+    pub fn effective_addresses(&self, instruction: &Instruction) -> EffectiveAddresses {
+        let resolve = |adr: &Address, depth: u8| {
+            (0..depth).fold(adr.location, |address, _| {
+                self.peek(address, Unit::Word) as u16
+            })
+        };
+        if let Some(usd) = instruction.usd() {
+            EffectiveAddresses {
+                destination: Some(resolve(&usd.destination, usd.destination.depth)),
+                source: match usd.source {
+                    Source::Pointer(ref adr) if adr.depth > 0 => Some(resolve(adr, adr.depth - 1)),
+                    _ => None,
+                },
+            }
+        } else {
+            EffectiveAddresses {
+                destination: instruction.address().map(|adr| resolve(adr, adr.depth)),
+                source: None,
+            }
+        }
+    }
+
+    /// Decodes the instruction at the instruction pointer without executing it.
+    pub fn fetch(&self) -> Result<(Instruction, u8), Fault> {
+        let address = self.ip;
+        let mut consumed = 0;
+        let res = {
+            let mut rest = (address as usize + 1..MEMORY_SIZE)
+                .map(|a| self.peek(a as u16, Unit::Byte) as u8)
+                .inspect(|_| consumed += 1);
+            Instruction::disassemble(self.peek(address, Unit::Byte) as u8, &mut rest)
+        };
+        match res {
+            Ok(ins) => Ok((ins, consumed + 1)),
+            Err(DisassembleError::InvalidInstruction(_)) => Err(Fault::InvalidInstruction(address)),
+            Err(DisassembleError::NotEnoughData(_)) => Err(Fault::TruncatedInstruction(address)),
+        }
+    }
+
+    /// Executes one instruction. Faults that are exceptions trapping into the program (see
+    /// `set_exception_policy`) are entered like an interrupt and reported in the step instead.
+    pub fn step(&mut self) -> Result<Step, Fault> {
+        let address = self.ip;
+        let mut step = Step {
+            address,
+            length: 0,
+            instruction: None,
+            effective: EffectiveAddresses::default(),
+            reads: Vec::new(),
+            writes: Vec::new(),
+            exception: None,
+            cycles: 0,
+        };
+        self.reads = Some(Vec::new());
+        self.writes = Some(Vec::new());
+        let res = self.fetch().and_then(|(instruction, length)| {
+            self.ip = address.wrapping_add(length as u16);
+            step.effective = self.effective_addresses(&instruction);
+            step.cycles = self.costs.cost(&instruction);
+            let res = self.execute(address, &instruction);
+            step.length = length;
+            step.instruction = Some(instruction);
+            res
+        });
+        let res = res
+            .or_else(|fault| {
+                let next = self.ip;
+                // Leave the faulting instruction as the current one.
+                self.ip = address;
+                step.exception = Some(self.trap(fault, next)?);
+                Ok(())
+            })
+            .and_then(|_| self.tick_devices());
+        step.reads = self.reads.take().unwrap_or_default();
+        step.writes = self.writes.take().unwrap_or_default();
+        res?;
+        self.steps += 1;
+        self.cycles += step.cycles as u64;
+        Ok(step)
+    }
+
+    /// Executes at most `max_steps` instructions, stopping early if the machine halts, and
+    /// returns how many were executed.
+    pub fn run(&mut self, max_steps: u64) -> Result<u64, Fault> {
+        let mut steps = 0;
+        while steps < max_steps && !self.halted {
+            self.step()?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    fn execute(&mut self, address: u16, instruction: &Instruction) -> Result<(), Fault> {
+        self.dispatch(address, instruction)
+            .map_err(|err| match err {
+                ExecError::Fault(fault) => fault,
+                ExecError::Unmapped(Unmapped(adr)) => Fault::UnmappedAccess(address, adr),
+            })
+    }
+
+    fn dispatch(&mut self, address: u16, instruction: &Instruction) -> Result<(), ExecError> {
+        use Instruction::*;
+        match *instruction {
+            Mov(ref usd) => self.assign(usd, |src| src)?,
+            Add(ref usd) => self.arithmetic(usd, |dst, src| dst + src)?,
+            Sub(ref usd) => self.arithmetic(usd, |dst, src| dst - src)?,
+            Mul(ref usd) => self.arithmetic(usd, |dst, src| dst * src)?,
+            Div(ref usd) => {
+                let (dest, dst, src) = self.operands(usd)?;
+                if src == 0 {
+                    return Err(Fault::DivisionByZero(address).into());
+                }
+                self.checked_write(dest, usd.unit, dst / src)?
+            }
+            Cmp(ref usd) => {
+                let (_, dst, src) = self.operands(usd)?;
+                self.flags = Flags::compare(dst, src, usd.unit);
+            }
+            Jg(ref adr) => self.jump_if(adr, self.flags.greater)?,
+            Je(ref adr) => self.jump_if(adr, self.flags.equal)?,
+            Jl(ref adr) => self.jump_if(adr, self.flags.less)?,
+            Jmp(ref adr) => self.jump_if(adr, true)?,
+            Int(id) => {
+                if !self.host_interrupt(id) {
+                    self.interrupt(address, id)?
+                }
+            }
+            Iret => self.iret(address)?,
+            And(ref usd) => self.apply(usd, |dst, src| dst & src)?,
+            Or(ref usd) => self.apply(usd, |dst, src| dst | src)?,
+            Xor(ref usd) => self.apply(usd, |dst, src| dst ^ src)?,
+            Not(ref usd) => self.assign(usd, |src| !src)?,
+            Shl(ref usd) => self.apply(usd, |dst, src| dst.checked_shl(src).unwrap_or(0))?,
+            Shr(ref usd) => self.apply(usd, |dst, src| dst.checked_shr(src).unwrap_or(0))?,
+        }
+        Ok(())
+    }
+
+    // The executor's memory accesses, which fail on unmapped addresses.
+
+    fn checked_read(&mut self, address: u16, unit: Unit) -> Result<u32, Unmapped> {
+        if !self.bus.is_mapped(address) {
+            return Err(Unmapped(address));
+        }
+        Ok(self.read(address, unit))
+    }
+
+    fn checked_write(&mut self, address: u16, unit: Unit, value: u32) -> Result<(), Unmapped> {
+        if !self.bus.is_mapped(address) {
+            return Err(Unmapped(address));
+        }
+        self.write(address, unit, value & mask(unit));
+        Ok(())
+    }
+
+    /// The destination address, the value there and the source value.
+    fn operands(&mut self, usd: &Usd) -> Result<(u16, u32, u32), Unmapped> {
+        let dest = self.resolve(&usd.destination)?;
+        let dst = self.checked_read(dest, usd.unit)?;
+        let src = self.source_value(&usd.source, usd.unit)?;
+        Ok((dest, dst, src))
+    }
+
+    /// Applies `op` to the destination and source and stores the result, truncated to the
+    /// instruction's unit.
+    fn apply<F: FnOnce(u32, u32) -> u32>(&mut self, usd: &Usd, op: F) -> Result<(), Unmapped> {
+        let (dest, dst, src) = self.operands(usd)?;
+        self.checked_write(dest, usd.unit, op(dst, src))
+    }
+
+    /// Like `apply` for operations that don't depend on the destination, which isn't read.
+    fn assign<F: FnOnce(u32) -> u32>(&mut self, usd: &Usd, op: F) -> Result<(), Unmapped> {
+        let dest = self.resolve(&usd.destination)?;
+        let src = self.source_value(&usd.source, usd.unit)?;
+        self.checked_write(dest, usd.unit, op(src))
+    }
+
+    /// Like `apply`, but also sets the carry and overflow flags. `op` gets the operands both
+    /// as unsigned and as signed numbers and must return the exact result.
+    fn arithmetic<F: Fn(i128, i128) -> i128>(&mut self, usd: &Usd, op: F) -> Result<(), Unmapped> {
+        let (dest, dst, src) = self.operands(usd)?;
+        let unsigned = op(dst as i128, src as i128);
+        let signed = op(
+            sign_extend(dst, usd.unit) as i128,
+            sign_extend(src, usd.unit) as i128,
+        );
+        self.checked_write(dest, usd.unit, unsigned as u32)?;
+        self.flags.set_arithmetic(unsigned, signed, usd.unit);
+        Ok(())
+    }
+
+    fn jump_if(&mut self, adr: &Address, condition: bool) -> Result<(), Unmapped> {
+        if condition {
+            self.ip = self.resolve(adr)?;
+        }
+        Ok(())
+    }
+
+    /// Queues the interrupts devices raise and enters the oldest one. Device interrupts without
+    /// a handler are dropped.
+    fn tick_devices(&mut self) -> Result<(), Fault> {
+        self.pending.extend(self.bus.tick());
+        if let Some(id) = self.pending.pop_front() {
+            if !self.host_interrupt(id) {
+                match self.interrupt(self.ip, id) {
+                    Err(Fault::UnregisteredInterrupt(..)) => {}
+                    res => res?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn mask(unit: Unit) -> u32 {
+    match unit {
+        Unit::Byte => 0xFF,
+        Unit::Word => 0xFFFF,
+        Unit::Dword => 0xFFFF_FFFF,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn machine(program: &[Instruction]) -> Machine {
+        let (binary, _) = assemble(program).unwrap();
+        let mut machine = Machine::new();
+        machine.load(0, &binary);
+        machine
+    }
+
+    fn usd(unit: Unit, source: Source, location: u16, depth: u8) -> Usd {
+        Usd {
+            unit,
+            source,
+            destination: Address { location, depth },
+        }
+    }
+
+    fn ptr(location: u16, depth: u8) -> Source {
+        Source::Pointer(Address { location, depth })
+    }
+
+    #[test]
+    fn test_mov_depths() {
+        let mut m = machine(&[
+            Instruction::Mov(usd(Unit::Word, Source::Value(0x1234), 0x100, 0)),
+            Instruction::Mov(usd(Unit::Word, Source::Value(0x200), 0x102, 0)),
+            Instruction::Mov(usd(Unit::Byte, ptr(0x100, 1), 0x102, 1)),
+            Instruction::Mov(usd(Unit::Word, ptr(0x102, 2), 0x300, 0)),
+            Instruction::Mov(usd(Unit::Word, ptr(0xBEEF, 0), 0x302, 0)),
+        ]);
+        m.run(5).unwrap();
+        assert_eq!(m.read(0x100, Unit::Word), 0x1234);
+        assert_eq!(m.read(0x200, Unit::Byte), 0x12);
+        assert_eq!(m.read(0x300, Unit::Word), 0x1200);
+        assert_eq!(m.read(0x302, Unit::Word), 0xBEEF);
+    }
+
+    #[test]
+    fn test_arithmetic_wraps_to_unit() {
+        let mut m = machine(&[
+            Instruction::Mov(usd(Unit::Byte, Source::Value(250), 0x100, 0)),
+            Instruction::Add(usd(Unit::Byte, Source::Value(10), 0x100, 0)),
+            Instruction::Mov(usd(Unit::Word, Source::Value(7), 0x102, 0)),
+            Instruction::Sub(usd(Unit::Word, Source::Value(8), 0x102, 0)),
+            Instruction::Mov(usd(Unit::Dword, Source::Value(100), 0x104, 0)),
+            Instruction::Mul(usd(Unit::Dword, Source::Value(3), 0x104, 0)),
+            Instruction::Div(usd(Unit::Dword, Source::Value(7), 0x104, 0)),
+            Instruction::Shl(usd(Unit::Byte, Source::Value(4), 0x100, 0)),
+            Instruction::Not(usd(Unit::Word, Source::Value(0xF0F0), 0x108, 0)),
+            Instruction::Xor(usd(Unit::Word, Source::Value(0xFFFF), 0x108, 0)),
+        ]);
+        m.run(10).unwrap();
+        assert_eq!(m.read(0x100, Unit::Byte), 0x40);
+        assert_eq!(m.read(0x102, Unit::Word), 0xFFFF);
+        assert_eq!(m.read(0x104, Unit::Dword), 42);
+        assert_eq!(m.read(0x108, Unit::Word), 0xF0F0);
+        assert_eq!(m.read(0x10A, Unit::Byte), 0);
+    }
+
+    #[test]
+    fn test_cmp_and_jumps() {
+        // 0: cmp (5 bytes), 5: jl 0x20 (3 bytes), 8: jmp @0x104
+        let mut m = machine(&[
+            Instruction::Cmp(usd(Unit::Byte, Source::Value(1), 0x100, 0)),
+            Instruction::Jl(Address {
+                location: 0x20,
+                depth: 0,
+            }),
+            Instruction::Jmp(Address {
+                location: 0x104,
+                depth: 1,
+            }),
+        ]);
+        m.write(0x104, Unit::Word, 0x30);
+        m.run(2).unwrap();
+        assert!(m.flags().less);
+        assert_eq!(m.ip(), 0x20);
+
+        m.set_ip(8);
+        m.step().unwrap();
+        assert_eq!(m.ip(), 0x30);
+    }
+
+    #[test]
+    fn test_int_iret() {
+        let mut m = machine(&[Instruction::Int(0x80), Instruction::Iret]);
+        let vector = m.vector_table().entry(0x80);
+        m.write(vector, Unit::Word, 0x2);
+        let step = m.step().unwrap();
+        assert_eq!(step.length, 2);
+        assert_eq!(m.ip(), 2);
+        assert_eq!(m.interrupts()[0].return_address, 2);
+        m.step().unwrap();
+        assert_eq!(m.ip(), 2);
+        assert_eq!(m.step(), Err(Fault::IretWithoutInt(2)));
+    }
+
+    #[test]
+    fn test_nested_interrupts() {
+        // 0: int 0x10, 2: cmp, 7: int 0x11, 9: iret, 10: iret
+        let mut m = machine(&[
+            Instruction::Int(0x10),
+            Instruction::Cmp(usd(Unit::Byte, Source::Value(1), 0x300, 0)),
+            Instruction::Int(0x11),
+            Instruction::Iret,
+            Instruction::Iret,
+        ]);
+        m.set_vector_table(VectorTable {
+            base: 0x200,
+            stride: 2,
+        });
+        m.write(0x220, Unit::Word, 2);
+        m.write(0x222, Unit::Word, 10);
+
+        m.run(3).unwrap();
+        let frames: Vec<_> = m
+            .interrupts()
+            .iter()
+            .map(|f| (f.id, f.return_address))
+            .collect();
+        assert_eq!(frames, vec![(0x10, 2), (0x11, 9)]);
+        assert!(m.flags().less);
+
+        // The inner iret restores the flags set in the outer handler, the outer one the flags
+        // from before the interrupt.
+        m.step().unwrap();
+        assert_eq!((m.ip(), m.flags().less), (9, true));
+        m.step().unwrap();
+        assert_eq!((m.ip(), m.flags()), (2, Flags::default()));
+        assert!(m.interrupts().is_empty());
+
+        m.set_ip(0);
+        m.write(0x220, Unit::Word, 0);
+        assert_eq!(m.step(), Err(Fault::UnregisteredInterrupt(0, 0x10)));
+
+        assert_eq!(m.ip(), 0);
+
+        // A handler that interrupts itself.
+        m.set_vector_table(VectorTable::default());
+        let vector = m.vector_table().entry(0x10);
+        m.write(vector, Unit::Word, 0x40);
+        m.write(0x40, Unit::Word, 0x3010);
+        assert_eq!(m.run(100), Err(Fault::InterruptOverflow(0x40)));
+        assert_eq!(m.interrupts().len(), MAX_NESTING);
+    }
+
+    #[test]
+    fn test_adjacent_vectors() {
+        // 0: int 0x10, 2: int 0x11, 4: iret, 5: iret
+        let mut m = machine(&[
+            Instruction::Int(0x10),
+            Instruction::Int(0x11),
+            Instruction::Iret,
+            Instruction::Iret,
+        ]);
+        let vectors = m.vector_table();
+        m.write(vectors.entry(0x10), Unit::Word, 4);
+        m.write(vectors.entry(0x11), Unit::Word, 5);
+
+        m.step().unwrap();
+        assert_eq!(m.ip(), 4);
+        m.run(2).unwrap();
+        assert_eq!(m.ip(), 5);
+        assert_eq!(m.interrupts()[0].id, 0x11);
+    }
+
+    #[test]
+    fn test_spec_vector() {
+        // Registered like in EMPU_spec.asm, by a program loaded at 0.
+        let mut m = machine(&[
+            Instruction::Mov(usd(Unit::Word, Source::Value(8), 0xEE, 0)),
+            Instruction::Int(0xEE),
+            Instruction::Iret,
+        ]);
+        m.run(2).unwrap();
+        assert_eq!(m.ip(), 8);
+        assert_eq!(m.interrupts()[0].id, 0xEE);
+        m.step().unwrap();
+        assert_eq!(m.ip(), 8);
+        assert!(m.interrupts().is_empty());
+    }
+
+    #[test]
+    fn test_faults() {
+        let mut m = machine(&[Instruction::Div(usd(
+            Unit::Byte,
+            Source::Value(0),
+            0x100,
+            0,
+        ))]);
+        assert_eq!(m.step(), Err(Fault::DivisionByZero(0)));
+
+        let mut m = Machine::new();
+        m.load(0, &[0xF0]);
+        assert_eq!(m.step(), Err(Fault::InvalidInstruction(0)));
+
+        m.load(0xFFFE, &[0b00100011]);
+        assert_eq!(m.step(), Err(Fault::TruncatedInstruction(0xFFFE)));
+    }
+}
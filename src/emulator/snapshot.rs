@@ -0,0 +1,421 @@
+use super::*;
+
+use std::ops::RangeInclusive;
+
+const MAGIC: &[u8; 4] = b"EMPU";
+
+/// The format version `Snapshot::to_bytes` writes and `Snapshot::from_bytes` reads.
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    Truncated,
+    TrailingData,
+    // The device isn't attached or didn't take its state.
+    Device(DeviceId),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SnapshotError::BadMagic => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingData => write!(f, "snapshot has trailing data"),
+            SnapshotError::Device(DeviceId(id)) => {
+                write!(f, "device {} cannot be restored", id)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// A frozen machine: its memory, registers, interrupt state and the devices that opt in
+/// through `Device::save`.
+///
+/// Host interrupt handlers and the layout of the bus aren't part of it, so a snapshot should be
+/// restored into a machine set up like the one it was taken from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub state: CpuState,
+    pub vectors: VectorTable,
+    pub traps: Vec<Exception>,
+    pub ram: Vec<u8>,
+    pub devices: Vec<(DeviceId, Vec<u8>)>,
+}
+
+impl Snapshot {
+    /// Encodes the snapshot, big-endian like the machine itself.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        put_u16(&mut out, SNAPSHOT_VERSION);
+
+        let state = &self.state;
+        put_u16(&mut out, state.ip);
+        out.push(state.flags.bits());
+        out.push(state.halted as u8);
+        out.extend_from_slice(&state.steps.to_be_bytes());
+        out.extend_from_slice(&state.cycles.to_be_bytes());
+        put_u16(&mut out, state.frames.len() as u16);
+        for frame in &state.frames {
+            out.push(frame.id);
+            put_u16(&mut out, frame.return_address);
+            out.push(frame.flags.bits());
+        }
+        put_u16(&mut out, state.pending.len() as u16);
+        out.extend_from_slice(&state.pending);
+
+        put_u16(&mut out, self.vectors.base);
+        put_u16(&mut out, self.vectors.stride);
+        out.push(
+            self.traps
+                .iter()
+                .fold(0, |bits, exception| bits | 1 << exception.vector()),
+        );
+
+        out.extend_from_slice(&self.ram);
+        put_u16(&mut out, self.devices.len() as u16);
+        for &(DeviceId(id), ref device) in &self.devices {
+            put_u16(&mut out, id as u16);
+            out.extend_from_slice(&(device.len() as u32).to_be_bytes());
+            out.extend_from_slice(device);
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let mut r = Reader { bytes };
+        if r.take(MAGIC.len())? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        match r.u16()? {
+            SNAPSHOT_VERSION => {}
+            version => return Err(SnapshotError::UnsupportedVersion(version)),
+        }
+
+        let ip = r.u16()?;
+        let flags = Flags::from_bits(r.u8()?);
+        let halted = r.u8()? != 0;
+        let steps = r.u64()?;
+        let cycles = r.u64()?;
+        let frames = (0..r.u16()?)
+            .map(|_| {
+                Ok(Frame {
+                    id: r.u8()?,
+                    return_address: r.u16()?,
+                    flags: Flags::from_bits(r.u8()?),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let n = r.u16()? as usize;
+        let pending = r.take(n)?.to_vec();
+
+        let vectors = VectorTable {
+            base: r.u16()?,
+            stride: r.u16()?,
+        };
+        let traps = r.u8()?;
+        let traps = Exception::ALL
+            .iter()
+            .cloned()
+            .filter(|exception| traps & 1 << exception.vector() != 0)
+            .collect();
+
+        let ram = r.take(MEMORY_SIZE)?.to_vec();
+        let devices = (0..r.u16()?)
+            .map(|_| {
+                let id = DeviceId(r.u16()? as usize);
+                let n = r.u32()? as usize;
+                Ok((id, r.take(n)?.to_vec()))
+            })
+            .collect::<Result<_, _>>()?;
+        if !r.bytes.is_empty() {
+            return Err(SnapshotError::TrailingData);
+        }
+
+        Ok(Snapshot {
+            state: CpuState {
+                ip,
+                flags,
+                frames,
+                halted,
+                pending,
+                steps,
+                cycles,
+            },
+            vectors,
+            traps,
+            ram,
+            devices,
+        })
+    }
+
+    /// The memory ranges that differ from `other`, in address order.
+    pub fn diff(&self, other: &Snapshot) -> Vec<RangeInclusive<u16>> {
+        let mut ranges: Vec<RangeInclusive<u16>> = Vec::new();
+        let changed = self
+            .ram
+            .iter()
+            .zip(&other.ram)
+            .enumerate()
+            .filter(|&(_, (a, b))| a != b)
+            .map(|(address, _)| address as u16);
+        for address in changed {
+            match ranges.last_mut() {
+                Some(range) if range.end().wrapping_add(1) == address => {
+                    *range = *range.start()..=address;
+                }
+                _ => ranges.push(address..=address),
+            }
+        }
+        ranges
+    }
+}
+
+impl Machine {
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            state: self.cpu_state(),
+            vectors: self.vectors,
+            traps: self.traps.clone(),
+            ram: self.memory().to_vec(),
+            devices: self.bus.save_devices(),
+        }
+    }
+
+    /// Puts the machine back into the state of `snapshot`. If that fails, the machine is left
+    /// as it was.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+        // The devices' current states, to put back if one of them rejects its new state.
+        let saved = self.bus.save_devices();
+        let current = |id| saved.iter().find(|&&(saved, _)| saved == id);
+        for &(id, _) in &snapshot.devices {
+            if current(id).is_none() {
+                return Err(SnapshotError::Device(id));
+            }
+        }
+        for (i, &(id, ref state)) in snapshot.devices.iter().enumerate() {
+            if !self.bus.restore_device(id, state) {
+                for &(id, _) in snapshot.devices[..i].iter().rev() {
+                    let state = &current(id).unwrap().1;
+                    self.bus.restore_device(id, state);
+                }
+                return Err(SnapshotError::Device(id));
+            }
+        }
+        self.set_cpu_state(snapshot.state.clone());
+        self.vectors = snapshot.vectors;
+        self.traps = snapshot.traps.clone();
+        self.memory_mut().copy_from_slice(&snapshot.ram);
+        Ok(())
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, SnapshotError> {
+        Ok(self.take(2)?.iter().fold(0, |n, &b| n << 8 | b as u16))
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(self.take(4)?.iter().fold(0, |n, &b| n << 8 | b as u32))
+    }
+
+    fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(self.take(8)?.iter().fold(0, |n, &b| n << 8 | b as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    // Counts ticks, and saves the count if it opts into snapshots.
+    struct Clock {
+        ticks: u32,
+        saves: bool,
+    }
+
+    impl Device for Clock {
+        fn read(&mut self, offset: u16, unit: Unit) -> u32 {
+            self.peek(offset, unit)
+        }
+
+        fn write(&mut self, _: u16, _: Unit, _: u32) {}
+
+        fn peek(&self, _: u16, _: Unit) -> u32 {
+            self.ticks
+        }
+
+        fn tick(&mut self) -> Option<u8> {
+            self.ticks += 1;
+            None
+        }
+
+        fn save(&self) -> Option<Vec<u8>> {
+            if self.saves {
+                Some(self.ticks.to_be_bytes().to_vec())
+            } else {
+                None
+            }
+        }
+
+        fn restore(&mut self, state: &[u8]) -> bool {
+            if state.len() != 4 {
+                return false;
+            }
+            self.ticks = state.iter().fold(0, |n, &b| n << 8 | b as u32);
+            true
+        }
+    }
+
+    // Copies the clock into memory, counting 0x300 up, inside an interrupt handler.
+    fn machine() -> Machine {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0xEE, handler
+.loop:
+    int 0xEE
+    jmp .loop
+handler:
+    mov dword 0x304, @0xFF00
+    add 0x300, 1
+    iret
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        let clock = Clock {
+            ticks: 0,
+            saves: true,
+        };
+        m.bus_mut().attach(0xFF00..=0xFF03, clock).unwrap();
+        let clock = Clock {
+            ticks: 0,
+            saves: false,
+        };
+        m.bus_mut().attach(0xFF10..=0xFF13, clock).unwrap();
+        m.set_exception_policy(Exception::FetchPastEnd, ExceptionPolicy::Trap);
+        m
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut m = machine();
+        m.run(3).unwrap();
+        assert_eq!(m.interrupts().len(), 1);
+        let snapshot = m.snapshot();
+        assert_eq!(snapshot.devices.len(), 1);
+        let bytes = snapshot.to_bytes();
+        assert_eq!(Snapshot::from_bytes(&bytes), Ok(snapshot.clone()));
+
+        m.run(20).unwrap();
+        let expected = m.snapshot();
+
+        let mut fork = machine();
+        fork.restore(&Snapshot::from_bytes(&bytes).unwrap())
+            .unwrap();
+        assert_eq!(fork.snapshot(), snapshot);
+        fork.run(20).unwrap();
+        assert_eq!(fork.snapshot(), expected);
+
+        // The clock that doesn't save kept running in the original machine only.
+        assert_eq!(m.peek(0xFF10, Unit::Dword), 23);
+        assert_eq!(fork.peek(0xFF10, Unit::Dword), 20);
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut m = machine();
+        let before = m.snapshot();
+        m.run(5).unwrap();
+        let after = m.snapshot();
+        // The vector, the counter and the copied clock.
+        assert_eq!(
+            before.diff(&after),
+            vec![0xEE..=0xEF, 0x301..=0x301, 0x307..=0x307]
+        );
+        assert_eq!(after.diff(&after), vec![]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut bytes = machine().snapshot().to_bytes();
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        bytes.push(0);
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::TrailingData)
+        );
+        bytes[5] = 2;
+        assert_eq!(
+            Snapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnsupportedVersion(2))
+        );
+        assert_eq!(Snapshot::from_bytes(b"ELF"), Err(SnapshotError::Truncated));
+        assert_eq!(
+            Snapshot::from_bytes(b"\x7FELF"),
+            Err(SnapshotError::BadMagic)
+        );
+
+        let snapshot = machine().snapshot();
+        assert_eq!(
+            Machine::new().restore(&snapshot),
+            Err(SnapshotError::Device(snapshot.devices[0].0))
+        );
+    }
+
+    #[test]
+    fn test_failed_restore() {
+        let mut m = machine();
+        m.run(5).unwrap();
+        let before = m.snapshot();
+
+        // The clock takes its first state, then rejects the second one.
+        let mut snapshot = machine().snapshot();
+        let id = snapshot.devices[0].0;
+        snapshot.devices.push((id, vec![1]));
+        assert_eq!(m.restore(&snapshot), Err(SnapshotError::Device(id)));
+        assert_eq!(m.snapshot(), before);
+        assert_eq!(m.peek(0xFF00, Unit::Dword), 5);
+
+        // The clock that doesn't save can't be restored.
+        let mut snapshot = machine().snapshot();
+        let id = DeviceId(1);
+        snapshot.devices.push((id, vec![0; 4]));
+        assert_eq!(m.restore(&snapshot), Err(SnapshotError::Device(id)));
+        assert_eq!(m.snapshot(), before);
+    }
+}
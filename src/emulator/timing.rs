@@ -0,0 +1,157 @@
+use super::*;
+
+/// What instructions cost, in cycles.
+///
+/// An instruction costs the base cost of its opcode, plus the cost of its unit, plus
+/// `dereference` for every pointer its operands follow (the sum of their `@` depths), plus
+/// `immediate_byte` for every byte of immediate it carries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CostTable {
+    // By mnemonic, see `Instruction::instr_str`. Missing ones cost 1.
+    pub base: HashMap<&'static str, u32>,
+    // Indexed by `Unit::id`.
+    pub unit: [u32; 3],
+    pub dereference: u32,
+    pub immediate_byte: u32,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        let base = [
+            ("mov", 1),
+            ("add", 1),
+            ("sub", 1),
+            ("mul", 4),
+            ("div", 8),
+            ("cmp", 1),
+            ("jg", 2),
+            ("je", 2),
+            ("jl", 2),
+            ("jmp", 2),
+            ("int", 6),
+            ("iret", 6),
+            ("and", 1),
+            ("or", 1),
+            ("xor", 1),
+            ("not", 1),
+            ("shl", 1),
+            ("shr", 1),
+        ];
+        CostTable {
+            base: base.iter().cloned().collect(),
+            unit: [0, 0, 1],
+            dereference: 2,
+            immediate_byte: 1,
+        }
+    }
+}
+
+impl CostTable {
+    pub fn cost(&self, instruction: &Instruction) -> u32 {
+        let base = self.base.get(instruction.instr_str()).cloned().unwrap_or(1);
+        let (unit, depth, immediate) = match (instruction.usd(), instruction.address()) {
+            (Some(usd), _) => {
+                let (depth, immediate) = match usd.source {
+                    Source::Pointer(ref adr) => (adr.depth, 0),
+                    Source::Value(_) => (0, usd.unit.num_bytes()),
+                };
+                (
+                    self.unit[usd.unit.id() as usize],
+                    usd.destination.depth + depth,
+                    immediate,
+                )
+            }
+            (None, Some(adr)) => (0, adr.depth, 0),
+            (None, None) => match *instruction {
+                // Its id.
+                Instruction::Int(_) => (0, 0, 1),
+                _ => (0, 0, 0),
+            },
+        };
+        base + unit + self.dereference * depth as u32 + self.immediate_byte * immediate as u32
+    }
+}
+
+impl Machine {
+    pub fn cost_table(&self) -> &CostTable {
+        &self.costs
+    }
+
+    pub fn set_cost_table(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    /// The cycles spent on all executed instructions.
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Like `run`, but executes instructions until at least `max_cycles` cycles are spent, and
+    /// returns how many were. The last instruction may go over the limit.
+    pub fn run_cycles(&mut self, max_cycles: u64) -> Result<u64, Fault> {
+        let start = self.cycles;
+        while self.cycles - start < max_cycles && !self.halted {
+            self.step()?;
+        }
+        Ok(self.cycles - start)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    fn cost(costs: &CostTable, line: &str) -> u32 {
+        let assembly = assembler::assemble(line, 0).unwrap();
+        let mut m = Machine::new();
+        m.load(0, &assembly.binary);
+        costs.cost(&m.fetch().unwrap().0)
+    }
+
+    #[test]
+    fn test_costs() {
+        let costs = CostTable::default();
+        assert_eq!(cost(&costs, "mov 0x300, 1"), 3);
+        assert_eq!(cost(&costs, "mov 0x300, @0x302"), 3);
+        assert_eq!(cost(&costs, "mov byte 0x300, 1"), 2);
+        assert_eq!(cost(&costs, "mov dword 0x300, 1"), 6);
+        assert_eq!(cost(&costs, "mov @0x300, @@0x302"), 7);
+        assert_eq!(cost(&costs, "jmp @0x300"), 4);
+        assert_eq!(cost(&costs, "int 0x80"), 7);
+        assert_eq!(cost(&costs, "iret"), 6);
+
+        let mut costs = costs;
+        costs.base.insert("mul", 20);
+        costs.base.remove("mov");
+        assert_eq!(cost(&costs, "mul byte 0x300, 1"), 21);
+        assert_eq!(cost(&costs, "mov byte 0x300, 1"), 2);
+    }
+
+    #[test]
+    fn test_run_cycles() {
+        let assembly = assembler::assemble(
+            "main:
+    add 0x300, 1
+    jmp main
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+
+        // 3 + 2 per iteration, stopping after the add that reaches 8.
+        assert_eq!(m.run_cycles(8), Ok(8));
+        assert_eq!(m.run_cycles(1), Ok(2));
+        assert_eq!((m.steps(), m.cycles()), (4, 10));
+
+        m.set_cost_table(CostTable {
+            immediate_byte: 0,
+            ..CostTable::default()
+        });
+        assert_eq!(m.run_cycles(4), Ok(4));
+        assert_eq!(m.cycles(), 14);
+    }
+}
@@ -1,47 +1,119 @@
 use super::*;
 
-use std::fmt::{Error as FmtError, Formatter};
-use std::iter;
+use std::fmt::{self, Error as FmtError, Formatter};
 
 impl Instruction {
     pub fn format_asm(&self, fmt: &mut Formatter) -> Result<(), FmtError> {
+        self.format_asm_with(fmt, |_| None)
+    }
+
+    /// Like `format_asm`, but writes the name `name` returns for an address instead of the
+    /// address. Immediates are left alone, even if they hold an address.
+    pub fn format_asm_with<F>(&self, fmt: &mut Formatter, name: F) -> Result<(), FmtError>
+    where
+        F: Fn(u16) -> Option<String>,
+    {
+        let address = |location: u16| name(location).unwrap_or_else(|| format!("0x{:X}", location));
         write!(fmt, "{}", self.instr_str().to_uppercase())?;
 
-        if let Some(ref usd) = self.usd() {
+        if let Some(usd) = self.usd() {
             write!(
                 fmt,
-                " {} {}0x{:X}, {}0x{:X}",
+                " {} {}{}, {}{}",
                 match usd.unit {
                     Unit::Byte => "byte",
                     Unit::Word => "word",
                     Unit::Dword => "dword",
                 },
                 indirection(usd.destination.depth as usize),
-                usd.destination.location,
+                address(usd.destination.location),
                 match usd.source {
                     Source::Value(..) => "".to_owned(),
                     Source::Pointer(ref adr) => indirection(adr.depth as usize),
                 },
                 match usd.source {
-                    Source::Value(ref v) => *v,
-                    Source::Pointer(ref p) => p.location as u32,
+                    Source::Value(ref v) => format!("0x{:X}", v),
+                    Source::Pointer(ref p) => address(p.location),
                 }
             )?;
-        } else if let Some(ref adr) = self.address() {
+        } else if let Some(adr) = self.address() {
             write!(
                 fmt,
-                " {}0x{:X}",
+                " {}{}",
                 indirection(adr.depth as usize),
-                adr.location
+                address(adr.location)
             )?;
-        } else if let &Instruction::Int(ref id) = self {
+        } else if let Instruction::Int(id) = self {
             write!(fmt, " 0x{:X}", id)?;
         }
 
         Ok(())
     }
+
+    /// Displays the instruction like `format_asm_with`.
+    pub fn symbolized<F: Fn(u16) -> Option<String>>(&self, name: F) -> Symbolized<'_, F> {
+        Symbolized {
+            instruction: self,
+            name,
+        }
+    }
+}
+
+pub struct Symbolized<'a, F> {
+    instruction: &'a Instruction,
+    name: F,
+}
+
+impl<'a, F: Fn(u16) -> Option<String>> fmt::Display for Symbolized<'a, F> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.instruction.format_asm_with(f, &self.name)
+    }
 }
 
 fn indirection(depth: usize) -> String {
-    iter::repeat("@").take(depth).collect()
+    "@".repeat(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_symbolized() {
+        let ins = Instruction::Add(Usd {
+            unit: Unit::Word,
+            source: Source::Pointer(Address {
+                location: 0x210,
+                depth: 2,
+            }),
+            destination: Address {
+                location: 0x300,
+                depth: 1,
+            },
+        });
+        let name = |address| {
+            if address == 0x210 {
+                Some("add.b".to_owned())
+            } else {
+                None
+            }
+        };
+        assert_eq!(ins.to_string(), "ADD word @0x300, @@0x210");
+        assert_eq!(ins.symbolized(name).to_string(), "ADD word @0x300, @@add.b");
+
+        let jmp = Instruction::Jmp(Address {
+            location: 0x210,
+            depth: 0,
+        });
+        assert_eq!(jmp.symbolized(name).to_string(), "JMP add.b");
+        let mov = Instruction::Mov(Usd {
+            unit: Unit::Byte,
+            source: Source::Value(0x210),
+            destination: Address {
+                location: 0x210,
+                depth: 0,
+            },
+        });
+        assert_eq!(mov.symbolized(name).to_string(), "MOV byte add.b, 0x210");
+    }
 }
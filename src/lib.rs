@@ -1,14 +1,20 @@
-use std::io::Result as IoResult;
 use std::collections::HashMap;
 
 mod assemble;
 mod disassemble;
 mod format_asm;
 pub mod assembler;
+pub mod coverage;
+pub mod debugger;
+pub mod emulator;
+pub mod profile;
+pub mod trace;
 
+pub use assemble::{EncodeError, Operand};
 pub use disassemble::*;
+pub use format_asm::Symbolized;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instruction {
     Mov(Usd),
     Add(Usd),
@@ -33,25 +39,25 @@ pub enum Instruction {
 impl Instruction {
     pub fn instr_str(&self) -> &'static str {
         use Instruction::*;
-        match self {
-            &Mov(..) => "mov",
-            &Add(..) => "add",
-            &Sub(..) => "sub",
-            &Mul(..) => "mul",
-            &Div(..) => "div",
-            &Cmp(..) => "cmp",
-            &Jg(..) => "jg",
-            &Je(..) => "je",
-            &Jl(..) => "jl",
-            &Jmp(..) => "jmp",
-            &Int(..) => "int",
-            &Iret => "iret",
-            &And(..) => "and",
-            &Or(..) => "or",
-            &Xor(..) => "xor",
-            &Not(..) => "not",
-            &Shl(..) => "shl",
-            &Shr(..) => "shr",
+        match *self {
+            Mov(..) => "mov",
+            Add(..) => "add",
+            Sub(..) => "sub",
+            Mul(..) => "mul",
+            Div(..) => "div",
+            Cmp(..) => "cmp",
+            Jg(..) => "jg",
+            Je(..) => "je",
+            Jl(..) => "jl",
+            Jmp(..) => "jmp",
+            Int(..) => "int",
+            Iret => "iret",
+            And(..) => "and",
+            Or(..) => "or",
+            Xor(..) => "xor",
+            Not(..) => "not",
+            Shl(..) => "shl",
+            Shr(..) => "shr",
         }
     }
 
@@ -80,14 +86,14 @@ impl std::fmt::Display for Instruction {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Usd {
     pub unit: Unit,
     pub source: Source,
     pub destination: Address,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Unit {
     Byte,
     Word,
@@ -121,13 +127,13 @@ impl Unit {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Address {
     pub location: u16,
     pub depth: u8,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Source {
     Value(u32),
     Pointer(Address),
@@ -142,7 +148,7 @@ impl Source {
     }
 
     pub fn depth(&self) -> Option<u8> {
-        if let &Source::Pointer(ref adr) = self {
+        if let Source::Pointer(adr) = self {
             Some(adr.depth)
         } else {
             None
@@ -150,9 +156,9 @@ impl Source {
     }
 }
 
-pub fn assemble(
-    instructions: &[Instruction],
-) -> IoResult<(Vec<u8>, HashMap<usize, (usize, usize)>)> {
+pub type InstructionMap = HashMap<usize, (usize, usize)>;
+
+pub fn assemble(instructions: &[Instruction]) -> Result<(Vec<u8>, InstructionMap), EncodeError> {
     let mut binary = Vec::new();
     let mut map = HashMap::new();
 
@@ -0,0 +1,260 @@
+use assembler::symbols::SymbolTable;
+use emulator::{Fault, Frame, Machine, Step};
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Count {
+    pub executions: u64,
+    pub cycles: u64,
+}
+
+impl Count {
+    fn add(&mut self, other: Count) {
+        self.executions += other.executions;
+        self.cycles += other.cycles;
+    }
+}
+
+// The interrupts active while an instruction executed, outermost first, as their return
+// addresses and ids.
+type Context = Vec<(u16, u8)>;
+
+/// A row of the flat report: the instructions under a label or sub-label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReportRow {
+    // The qualified name, or the address if no label comes before it.
+    pub symbol: String,
+    // The innermost interrupt the instructions ran in, so that handlers are reported
+    // separately from the code they interrupted.
+    pub interrupt: Option<u8>,
+    pub count: Count,
+}
+
+/// Counts the executions and cycles of every instruction address, in every interrupt context
+/// it ran in.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    samples: BTreeMap<(Context, u16), Count>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Executes one instruction and records it.
+    pub fn step(&mut self, machine: &mut Machine) -> Result<Step, Fault> {
+        let frames = machine.interrupts().to_vec();
+        let step = machine.step()?;
+        self.record(&frames, &step);
+        Ok(step)
+    }
+
+    /// Like `Machine::run`, recording every instruction.
+    pub fn run(&mut self, machine: &mut Machine, max_steps: u64) -> Result<u64, Fault> {
+        let mut steps = 0;
+        while steps < max_steps && !machine.halted() {
+            self.step(machine)?;
+            steps += 1;
+        }
+        Ok(steps)
+    }
+
+    /// Records `step`, which executed while the interrupts `frames` were active.
+    pub fn record(&mut self, frames: &[Frame], step: &Step) {
+        let context = frames
+            .iter()
+            .map(|frame| (frame.return_address, frame.id))
+            .collect();
+        self.samples
+            .entry((context, step.address))
+            .or_default()
+            .add(Count {
+                executions: 1,
+                cycles: step.cycles as u64,
+            });
+    }
+
+    /// The counts by instruction address, over all contexts.
+    pub fn addresses(&self) -> BTreeMap<u16, Count> {
+        let mut addresses: BTreeMap<u16, Count> = BTreeMap::new();
+        for (&(_, address), &count) in &self.samples {
+            addresses.entry(address).or_default().add(count);
+        }
+        addresses
+    }
+
+    pub fn total(&self) -> Count {
+        let mut total = Count::default();
+        for &count in self.samples.values() {
+            total.add(count);
+        }
+        total
+    }
+
+    /// The counts by label and interrupt, most cycles first.
+    pub fn report(&self, symbols: &SymbolTable) -> Vec<ReportRow> {
+        let names = Names::new(symbols);
+        let mut rows: BTreeMap<(String, Option<u8>), Count> = BTreeMap::new();
+        for (&(ref context, address), &count) in &self.samples {
+            let interrupt = context.last().map(|&(_, id)| id);
+            rows.entry((names.get(address), interrupt))
+                .or_default()
+                .add(count);
+        }
+        let mut rows: Vec<_> = rows
+            .into_iter()
+            .map(|((symbol, interrupt), count)| ReportRow {
+                symbol,
+                interrupt,
+                count,
+            })
+            .collect();
+        rows.sort_by_key(|row| std::cmp::Reverse(row.count.cycles));
+        rows
+    }
+
+    /// Writes `report` as a table.
+    pub fn write_report<W: Write>(&self, symbols: &SymbolTable, mut out: W) -> io::Result<()> {
+        let total = self.total().cycles.max(1) as f64;
+        writeln!(out, "    cycles        %  executions  symbol")?;
+        for row in self.report(symbols) {
+            write!(
+                out,
+                "{:>10}  {:>6.2}%  {:>10}  {}",
+                row.count.cycles,
+                row.count.cycles as f64 * 100.0 / total,
+                row.count.executions,
+                row.symbol
+            )?;
+            match row.interrupt {
+                Some(id) => writeln!(out, "  [int 0x{:02X}]", id)?,
+                None => writeln!(out)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the cycles as folded stacks, one `frame;frame;... cycles` line per stack, like
+    /// flame graph tools read them. Interrupt handlers sit on top of the code they interrupted,
+    /// separated by an `int 0xID` frame.
+    pub fn write_folded<W: Write>(&self, symbols: &SymbolTable, mut out: W) -> io::Result<()> {
+        let names = Names::new(symbols);
+        let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+        for (&(ref context, address), count) in &self.samples {
+            let mut frames = Vec::new();
+            for &(return_address, id) in context {
+                frames.push(names.get(return_address));
+                frames.push(format!("int 0x{:02X}", id));
+            }
+            frames.push(names.get(address));
+            *stacks.entry(frames.join(";")).or_insert(0) += count.cycles;
+        }
+        for (stack, cycles) in stacks {
+            writeln!(out, "{} {}", stack, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+// The qualified names sorted by address, built once to look up the symbols of many addresses.
+struct Names(Vec<(u16, String)>);
+
+impl Names {
+    fn new(symbols: &SymbolTable) -> Self {
+        let names = symbols.qualified().into_iter();
+        Names(names.map(|(name, sym)| (sym.address, name)).collect())
+    }
+
+    // Like `SymbolTable::containing`, or the address if no label comes before it.
+    fn get(&self, address: u16) -> String {
+        match self.0.partition_point(|&(start, _)| start <= address) {
+            0 => format!("0x{:04X}", address),
+            i => self.0[i - 1].1.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    #[test]
+    fn test_profile() {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0xEE, handler
+.loop:
+    int 0xEE
+    add 0x300, 1
+    jmp .loop
+handler:
+    add 0x302, 1
+    iret
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        let mut profiler = Profiler::new();
+        // The mov and two iterations.
+        assert_eq!(profiler.run(&mut m, 11), Ok(11));
+
+        let count = |executions, cycles| Count { executions, cycles };
+        assert_eq!(profiler.total(), count(11, 45));
+        let loop_start = assembly.symbols.resolve("main.loop", None).unwrap().address;
+        assert_eq!(profiler.addresses()[&loop_start], count(2, 14));
+
+        let rows: Vec<_> = profiler
+            .report(&assembly.symbols)
+            .into_iter()
+            .map(|row| (row.symbol, row.interrupt, row.count))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("main.loop".to_owned(), None, count(6, 24)),
+                ("handler".to_owned(), Some(0xEE), count(4, 18)),
+                ("main".to_owned(), None, count(1, 3)),
+            ]
+        );
+
+        let mut report = Vec::new();
+        profiler
+            .write_report(&assembly.symbols, &mut report)
+            .unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert_eq!(
+            report.lines().nth(2),
+            Some("        18   40.00%           4  handler  [int 0xEE]")
+        );
+
+        let mut folded = Vec::new();
+        profiler
+            .write_folded(&assembly.symbols, &mut folded)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(folded).unwrap(),
+            "main 3\nmain.loop 24\nmain.loop;int 0xEE;handler 18\n"
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        let assembly = assembler::assemble("jmp main\nmain:\n.start:\n    iret\n", 0x200).unwrap();
+        let names = Names::new(&assembly.symbols);
+        for &address in &[0, 0x200, 0x202, 0x203, 0x204, 0xFFFF] {
+            let expected = match assembly.symbols.containing(address) {
+                Some((name, _)) => name,
+                None => format!("0x{:04X}", address),
+            };
+            assert_eq!(names.get(address), expected);
+        }
+        assert_eq!(names.get(0x203), "main.start");
+    }
+}
@@ -0,0 +1,501 @@
+use super::*;
+
+use emulator::{mask, EffectiveAddresses, Fault, Machine, MemoryWrite};
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::ops::RangeInclusive;
+
+const MAGIC: &[u8; 4] = b"EMPT";
+
+/// The version of the binary trace format.
+pub const TRACE_VERSION: u16 = 1;
+
+/// The values the operands had when the instruction started.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperandValues {
+    // The value at the destination. `None` for jumps.
+    pub destination: Option<u32>,
+    pub source: Option<u32>,
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    // How many instructions were executed before this one.
+    pub step: u64,
+    pub address: u16,
+    // Empty if the instruction couldn't be decoded.
+    pub bytes: Vec<u8>,
+    pub instruction: Option<Instruction>,
+    pub effective: EffectiveAddresses,
+    pub values: OperandValues,
+    pub writes: Vec<MemoryWrite>,
+}
+
+impl TraceEntry {
+    /// Executes one instruction and records it.
+    pub fn step(machine: &mut Machine) -> Result<Self, Fault> {
+        let (bytes, values) = match machine.fetch() {
+            Ok((ins, length)) => {
+                let bytes = (0..length as u16)
+                    .map(|i| machine.peek(machine.ip().wrapping_add(i), Unit::Byte) as u8)
+                    .collect();
+                (bytes, operand_values(machine, &ins))
+            }
+            Err(_) => (Vec::new(), OperandValues::default()),
+        };
+        let step = machine.steps();
+        let executed = machine.step()?;
+        Ok(TraceEntry {
+            step,
+            address: executed.address,
+            bytes,
+            instruction: executed.instruction,
+            effective: executed.effective,
+            values,
+            writes: executed.writes,
+        })
+    }
+
+    /// Redoes the entry's writes to RAM.
+    pub fn apply(&self, machine: &mut Machine) {
+        for write in &self.writes {
+            for (i, byte) in write.bytes().into_iter().enumerate() {
+                machine.memory_mut()[write.address.wrapping_add(i as u16) as usize] = byte;
+            }
+        }
+    }
+}
+
+fn operand_values(machine: &Machine, instruction: &Instruction) -> OperandValues {
+    let usd = match instruction.usd() {
+        Some(usd) => usd,
+        None => return OperandValues::default(),
+    };
+    let effective = machine.effective_addresses(instruction);
+    let peek = |address: Option<u16>| address.map(|adr| machine.peek(adr, usd.unit));
+    OperandValues {
+        destination: peek(effective.destination),
+        source: match usd.source {
+            Source::Value(val) => Some(val & mask(usd.unit)),
+            Source::Pointer(ref adr) if adr.depth == 0 => {
+                Some(adr.location as u32 & mask(usd.unit))
+            }
+            Source::Pointer(_) => peek(effective.source),
+        },
+    }
+}
+
+impl fmt::Display for TraceEntry {
+    /// One line like `12  0200  05 20 03 00 00 01  ADD word 0x300, 0x1  dst 0300=4  src 1 ...`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let ins = self
+            .instruction
+            .as_ref()
+            .map_or("(invalid)".to_owned(), |ins| ins.to_string());
+        write!(
+            f,
+            "{:8}  {:04X}  {:<20}  {:<32}",
+            self.step,
+            self.address,
+            bytes.join(" "),
+            ins
+        )?;
+        let operands = [
+            ("dst", self.effective.destination, self.values.destination),
+            ("src", self.effective.source, self.values.source),
+        ];
+        for &(name, address, value) in &operands {
+            match (address, value) {
+                (Some(adr), Some(val)) => write!(f, "  {} {:04X}={:X}", name, adr, val)?,
+                (Some(adr), None) => write!(f, "  {} {:04X}", name, adr)?,
+                (None, Some(val)) => write!(f, "  {} {:X}", name, val)?,
+                (None, None) => {}
+            }
+        }
+        for write in &self.writes {
+            write!(
+                f,
+                "  [{:04X}] {:X} -> {:X}",
+                write.address, write.old, write.new
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    // One `TraceEntry` display per line.
+    Text,
+    // Compact, for `TraceReader`.
+    Binary,
+}
+
+#[derive(Debug)]
+pub enum TraceError {
+    Fault(Fault),
+    Io(io::Error),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TraceError::Fault(ref fault) => write!(f, "{}", fault),
+            TraceError::Io(ref err) => write!(f, "cannot write trace: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
+
+/// Writes a trace of every instruction it runs.
+pub struct Tracer<W> {
+    out: W,
+    format: TraceFormat,
+    // The step of the last entry, which binary entries are relative to.
+    last: Option<u64>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(mut out: W, format: TraceFormat) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&TRACE_VERSION.to_be_bytes())?;
+        }
+        Ok(Tracer {
+            out,
+            format,
+            last: None,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    /// Like `Machine::run`, tracing every instruction.
+    pub fn run(&mut self, machine: &mut Machine, max_steps: u64) -> Result<u64, TraceError> {
+        let mut steps = 0;
+        while steps < max_steps && !machine.halted() {
+            let entry = TraceEntry::step(machine).map_err(TraceError::Fault)?;
+            self.write(&entry).map_err(TraceError::Io)?;
+            steps += 1;
+        }
+        self.out.flush().map_err(TraceError::Io)?;
+        Ok(steps)
+    }
+
+    pub fn write(&mut self, entry: &TraceEntry) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Text => writeln!(self.out, "{}", entry),
+            TraceFormat::Binary => {
+                let mut buf = Vec::new();
+                let delta = match self.last {
+                    Some(last) => entry.step.wrapping_sub(last),
+                    None => entry.step,
+                };
+                self.last = Some(entry.step);
+                encode(&mut buf, delta, entry);
+                self.out.write_all(&buf)
+            }
+        }
+    }
+}
+
+// An entry is the step as a varint delta to the previous one, the address, the length and
+// bytes of the instruction, a byte saying which operands follow, the operands and the writes.
+fn encode(buf: &mut Vec<u8>, delta: u64, entry: &TraceEntry) {
+    put_varint(buf, delta);
+    buf.extend_from_slice(&entry.address.to_be_bytes());
+    buf.push(entry.bytes.len() as u8);
+    buf.extend_from_slice(&entry.bytes);
+
+    let (effective, values) = (entry.effective, entry.values);
+    let present = [
+        effective.destination.is_some(),
+        effective.source.is_some(),
+        values.destination.is_some(),
+        values.source.is_some(),
+    ];
+    buf.push(
+        present
+            .iter()
+            .enumerate()
+            .fold(0, |bits, (i, &set)| bits | (set as u8) << i),
+    );
+    for address in effective.destination.iter().chain(&effective.source) {
+        buf.extend_from_slice(&address.to_be_bytes());
+    }
+    for &value in values.destination.iter().chain(&values.source) {
+        put_varint(buf, value as u64);
+    }
+
+    put_varint(buf, entry.writes.len() as u64);
+    for write in &entry.writes {
+        buf.extend_from_slice(&write.address.to_be_bytes());
+        buf.push(write.unit.id());
+        put_varint(buf, write.old as u64);
+        put_varint(buf, write.new as u64);
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf.push(byte);
+            return;
+        }
+        buf.push(byte | 0x80);
+    }
+}
+
+/// Reads a binary trace back, entry by entry.
+pub struct TraceReader<R> {
+    input: R,
+    last: Option<u64>,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut input: R) -> io::Result<Self> {
+        let mut header = [0; 6];
+        input.read_exact(&mut header)?;
+        if &header[..4] != MAGIC {
+            return Err(invalid("not a trace"));
+        }
+        let version = u16::from_be_bytes([header[4], header[5]]);
+        if version != TRACE_VERSION {
+            return Err(invalid(&format!("unsupported trace version {}", version)));
+        }
+        Ok(TraceReader { input, last: None })
+    }
+
+    fn entry(&mut self, first: u8) -> io::Result<TraceEntry> {
+        let delta = self.varint(Some(first))?;
+        let step = match self.last {
+            Some(last) => last.wrapping_add(delta),
+            None => delta,
+        };
+        self.last = Some(step);
+        let address = self.u16()?;
+        let mut bytes = vec![0; self.u8()? as usize];
+        self.input.read_exact(&mut bytes)?;
+        let instruction = match bytes.split_first() {
+            Some((&b1, rest)) => Some(
+                Instruction::disassemble(b1, &mut rest.iter().cloned())
+                    .map_err(|err| invalid(&err.to_string()))?,
+            ),
+            None => None,
+        };
+
+        let present = self.u8()?;
+        let mut effective = EffectiveAddresses::default();
+        let mut values = OperandValues::default();
+        if present & 1 != 0 {
+            effective.destination = Some(self.u16()?);
+        }
+        if present & 2 != 0 {
+            effective.source = Some(self.u16()?);
+        }
+        if present & 4 != 0 {
+            values.destination = Some(self.varint(None)? as u32);
+        }
+        if present & 8 != 0 {
+            values.source = Some(self.varint(None)? as u32);
+        }
+
+        let writes = (0..self.varint(None)?)
+            .map(|_| {
+                Ok(MemoryWrite {
+                    address: self.u16()?,
+                    unit: Unit::from_id(self.u8()?).ok_or_else(|| invalid("invalid unit"))?,
+                    old: self.varint(None)? as u32,
+                    new: self.varint(None)? as u32,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(TraceEntry {
+            step,
+            address,
+            bytes,
+            instruction,
+            effective,
+            values,
+            writes,
+        })
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        let mut buf = [0];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let mut buf = [0; 2];
+        self.input.read_exact(&mut buf)?;
+        Ok(u16::from_be_bytes(buf))
+    }
+
+    fn varint(&mut self, first: Option<u8>) -> io::Result<u64> {
+        let mut byte = match first {
+            Some(byte) => byte,
+            None => self.u8()?,
+        };
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            value |= ((byte & 0x7F) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+            byte = self.u8()?;
+        }
+        Err(invalid("varint is too long"))
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut first = [0];
+        match self.input.read(&mut first) {
+            Ok(0) => None,
+            Ok(_) => Some(self.entry(first[0])),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Selects trace entries. Unset criteria match everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    pub addresses: Option<RangeInclusive<u16>>,
+    // Matched case-insensitively, like `add`.
+    pub mnemonic: Option<String>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, entry: &TraceEntry) -> bool {
+        let address = self
+            .addresses
+            .as_ref()
+            .is_none_or(|range| range.contains(&entry.address));
+        let mnemonic = self.mnemonic.as_ref().is_none_or(|m| {
+            entry
+                .instruction
+                .as_ref()
+                .is_some_and(|ins| ins.instr_str().eq_ignore_ascii_case(m))
+        });
+        address && mnemonic
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use assembler;
+
+    fn machine() -> Machine {
+        let assembly = assembler::assemble(
+            "main:
+    mov 0x110, 0x300
+    add @0x110, 0x1234
+    cmp 0x300, 0x2468
+    jl main
+.end:
+    jmp .end
+",
+            0x200,
+        )
+        .unwrap();
+        let mut m = Machine::new();
+        m.load(0x200, &assembly.binary);
+        m
+    }
+
+    #[test]
+    fn test_text() {
+        let mut tracer = Tracer::new(Vec::new(), TraceFormat::Text).unwrap();
+        tracer.run(&mut machine(), 2).unwrap();
+        let text = String::from_utf8(tracer.into_inner()).unwrap();
+        let lines: Vec<_> = text.lines().map(str::trim_end).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "       0  0200  05 00 01 10 03 00     MOV word 0x110, 0x300             \
+                 dst 0110=0  src 300  [0110] 0 -> 300",
+                "       1  0206  05 24 01 10 12 34     ADD word @0x110, 0x1234           \
+                 dst 0300=0  src 1234  [0300] 0 -> 1234",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut m = machine();
+        let mut tracer = Tracer::new(Vec::new(), TraceFormat::Binary).unwrap();
+        tracer.run(&mut m, 10).unwrap();
+        let bytes = tracer.into_inner();
+
+        let mut m = machine();
+        let expected: Vec<_> = (0..10).map(|_| TraceEntry::step(&mut m).unwrap()).collect();
+        let entries: Vec<_> = TraceReader::new(&bytes[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(entries, expected);
+        assert_eq!(entries[1].values.source, Some(0x1234));
+        assert_eq!(entries[5].effective.destination, Some(0x300));
+        assert_eq!(entries[5].values.destination, Some(0x1234));
+
+        // Replaying the writes rebuilds memory.
+        let mut replayed = machine();
+        for entry in &entries {
+            entry.apply(&mut replayed);
+        }
+        assert_eq!(replayed.memory(), m.memory());
+
+        let truncated = TraceReader::new(&bytes[..bytes.len() - 1]).unwrap();
+        assert!(truncated.last().unwrap().is_err());
+        assert!(TraceReader::new(&b"EMPU\x00\x01"[..]).is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut m = machine();
+        let entries: Vec<_> = (0..10).map(|_| TraceEntry::step(&mut m).unwrap()).collect();
+        let select = |filter: TraceFilter| -> Vec<u64> {
+            entries
+                .iter()
+                .filter(|e| filter.matches(e))
+                .map(|e| e.step)
+                .collect()
+        };
+        assert_eq!(select(TraceFilter::default()).len(), 10);
+        assert_eq!(
+            select(TraceFilter {
+                mnemonic: Some("ADD".to_owned()),
+                ..TraceFilter::default()
+            }),
+            vec![1, 5]
+        );
+        assert_eq!(
+            select(TraceFilter {
+                addresses: Some(0x20C..=0x218),
+                mnemonic: Some("jl".to_owned()),
+            }),
+            vec![3, 7]
+        );
+    }
+}
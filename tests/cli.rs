@@ -0,0 +1,54 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{self, Command, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Runs `empu args` with `input` on stdin, failing instead of hanging after a few seconds.
+fn empu(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_empu"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while child.try_wait().unwrap().is_none() {
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("`empu {}` did not finish", args.join(" "));
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn test_debug_read_char() {
+    let path = env::temp_dir().join(format!("empu-read-char-{}.asm", process::id()));
+    fs::write(&path, "main:\n    int 0x13\n    int 0x12\n").unwrap();
+    // The program reads the `A` between the commands.
+    let output = empu(
+        &["debug", path.to_str().unwrap()],
+        b"step\nAprint 0x101\nquit\n",
+    );
+    fs::remove_file(&path).unwrap();
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("0x101 = 0x41 (65)"), "{}", stderr);
+}
+
+#[test]
+fn test_error_limit_zero() {
+    let output = empu(&["asm", "--error-limit", "0"], b"");
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("`--error-limit` must be at least 1"),
+        "{}",
+        stderr
+    );
+}